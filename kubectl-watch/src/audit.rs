@@ -0,0 +1,417 @@
+use crate::input;
+use crate::kube::{Actor, Event};
+use crate::persistent::{self, Change, Export};
+
+use anyhow::Result;
+use kube::api::{DynamicObject, ObjectMeta, TypeMeta};
+use kube::ResourceExt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Verbs which write an object and answer with it
+const WRITE_VERBS: [&str; 4] = ["create", "update", "patch", "delete"];
+
+/// The fields of an `audit.k8s.io/v1` Event needed to make a revision of it
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuditEvent {
+    stage: String,
+    verb: String,
+    #[serde(default)]
+    user: UserInfo,
+    impersonated_user: Option<UserInfo>,
+    #[serde(default)]
+    user_agent: String,
+    #[serde(default)]
+    object_ref: ObjectReference,
+    response_status: Option<ResponseStatus>,
+    request_object: Option<Value>,
+    response_object: Option<Value>,
+    #[serde(default)]
+    stage_timestamp: String,
+}
+
+#[derive(Deserialize, Default)]
+struct UserInfo {
+    #[serde(default)]
+    username: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct ObjectReference {
+    #[serde(default)]
+    resource: String,
+    #[serde(default)]
+    namespace: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    api_group: String,
+    #[serde(default)]
+    api_version: String,
+    #[serde(default)]
+    resource_version: String,
+    #[serde(default)]
+    subresource: String,
+}
+
+impl ObjectReference {
+    /// Identifies the object across the events of the log
+    fn key(&self, namespace: &str, name: &str) -> String {
+        format!(
+            "{}/{}/{}/{}",
+            self.api_group, self.resource, namespace, name
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct ResponseStatus {
+    #[serde(default)]
+    code: u16,
+}
+
+/// Reads an api-server audit log of json lines from a file or stdin. Every successful
+/// write logged with its response body, i.e. at the RequestResponse level, becomes a
+/// revision of the written object, and every successful delete a tombstone.
+pub fn read(path: &str, export: Option<Export>) -> Result<Receiver<Event>> {
+    let reader = input::open(path)?;
+    let (tx, rx) = channel(32);
+    tokio::task::spawn_blocking(move || {
+        let mut log = AuditLog::default();
+        for line in BufReader::new(reader).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    tracing::warn!(%error, "stopped reading the audit log");
+                    return;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(event) => event,
+                Err(error) => {
+                    tracing::warn!(%error, "skipping invalid audit event");
+                    continue;
+                }
+            };
+            let event = match log.revision(event) {
+                Some(event) => event,
+                None => continue,
+            };
+            if let Event::Audited {
+                obj,
+                deleted,
+                actor,
+            } = &event
+            {
+                let change = match actor.verb.as_str() {
+                    _ if *deleted => Change::Deleted,
+                    "create" => Change::Added,
+                    _ => Change::Modified,
+                };
+                persistent::store_resource(&export, obj, change);
+            }
+            if tx.blocking_send(event).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// The last revision of every object seen in the log, for the tombstones of deletes
+/// which answer with a Status
+#[derive(Default)]
+struct AuditLog {
+    objects: HashMap<String, DynamicObject>,
+}
+
+impl AuditLog {
+    /// Makes a revision of a completed, successful write. Reads, failed requests and
+    /// writes other than deletes logged without the object they returned are left out.
+    fn revision(&mut self, event: AuditEvent) -> Option<Event> {
+        if event.stage != "ResponseComplete" || !WRITE_VERBS.contains(&event.verb.as_str()) {
+            return None;
+        }
+        if !event
+            .response_status
+            .as_ref()
+            .is_some_and(|status| (200..300).contains(&status.code))
+        {
+            return None;
+        }
+        // subresources like scale answer with an object of their own
+        if !event.object_ref.subresource.is_empty() && event.object_ref.subresource != "status" {
+            return None;
+        }
+        let deleted = event.verb == "delete";
+        let response = event
+            .response_object
+            .clone()
+            .and_then(|value| serde_json::from_value::<DynamicObject>(value).ok())
+            .filter(|obj| obj.metadata.name.is_some() && obj.metadata.resource_version.is_some());
+        let obj = match response {
+            Some(obj) => obj,
+            // a delete may answer with a Status instead of the deleted object
+            None if deleted => self.tombstone(&event)?,
+            None => return None,
+        };
+        let key = event
+            .object_ref
+            .key(&obj.namespace().unwrap_or_default(), &obj.name_any());
+        if deleted {
+            self.objects.remove(&key);
+        } else {
+            self.objects.insert(key, obj.clone());
+        }
+        Some(Event::Audited {
+            obj,
+            deleted,
+            actor: Actor {
+                user: match event.impersonated_user {
+                    Some(impersonated) => {
+                        format!("{} as {}", event.user.username, impersonated.username)
+                    }
+                    None => event.user.username,
+                },
+                verb: event.verb,
+                user_agent: event.user_agent,
+                timestamp: event.stage_timestamp,
+            },
+        })
+    }
+
+    /// Makes up the deleted object from the last revision seen of it, or else from the
+    /// object of the request or the reference to it. The kind is not logged, the
+    /// resource stands in for it then, and an unknown resourceVersion is recorded as 0.
+    fn tombstone(&self, event: &AuditEvent) -> Option<DynamicObject> {
+        let object_ref = &event.object_ref;
+        if object_ref.name.is_empty() {
+            return None;
+        }
+        let key = object_ref.key(&object_ref.namespace, &object_ref.name);
+        let request = event
+            .request_object
+            .clone()
+            .filter(|value| value.get("kind").and_then(Value::as_str) != Some("DeleteOptions"))
+            .and_then(|value| serde_json::from_value::<DynamicObject>(value).ok())
+            .filter(|obj| obj.metadata.name.is_some());
+        let mut obj = self
+            .objects
+            .get(&key)
+            .cloned()
+            .or(request)
+            .unwrap_or_else(|| {
+                let api_version = if object_ref.api_group.is_empty() {
+                    object_ref.api_version.clone()
+                } else {
+                    format!("{}/{}", object_ref.api_group, object_ref.api_version)
+                };
+                DynamicObject {
+                    types: Some(TypeMeta {
+                        api_version,
+                        kind: object_ref.resource.clone(),
+                    }),
+                    metadata: ObjectMeta::default(),
+                    data: Value::Object(Default::default()),
+                }
+            });
+        obj.metadata.name = Some(object_ref.name.clone());
+        if !object_ref.namespace.is_empty() {
+            obj.metadata.namespace = Some(object_ref.namespace.clone());
+        }
+        let uid = event
+            .response_object
+            .as_ref()
+            .and_then(|status| status.pointer("/details/uid"))
+            .and_then(Value::as_str);
+        if let Some(uid) = uid {
+            obj.metadata.uid = Some(uid.to_owned());
+        }
+        if obj.metadata.resource_version.is_none() {
+            let rv = match object_ref.resource_version.as_str() {
+                "" => "0",
+                rv => rv,
+            };
+            obj.metadata.resource_version = Some(rv.to_owned());
+        }
+        Some(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn audit_event(verb: &str, request: Value, response: Value) -> AuditEvent {
+        serde_json::from_value(json!({
+            "level": "RequestResponse",
+            "stage": "ResponseComplete",
+            "verb": verb,
+            "user": {"username": "alice"},
+            "userAgent": "kubectl/v1.29.0",
+            "objectRef": {
+                "resource": "configmaps",
+                "namespace": "default",
+                "name": "cm",
+                "apiVersion": "v1",
+            },
+            "responseStatus": {"code": 200},
+            "requestObject": request,
+            "responseObject": response,
+            "stageTimestamp": "2024-01-01T00:00:00.000000Z",
+        }))
+        .unwrap()
+    }
+
+    fn configmap(rv: &str) -> Value {
+        json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "cm", "namespace": "default", "uid": "1234", "resourceVersion": rv},
+            "data": {"key": rv},
+        })
+    }
+
+    fn status() -> Value {
+        json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Success",
+            "details": {"name": "cm", "kind": "configmaps", "uid": "1234"},
+        })
+    }
+
+    fn delete_options() -> Value {
+        json!({"kind": "DeleteOptions", "apiVersion": "meta.k8s.io/v1"})
+    }
+
+    fn audited(event: Option<Event>) -> (DynamicObject, bool) {
+        match event {
+            Some(Event::Audited { obj, deleted, .. }) => (obj, deleted),
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    fn actor(event: Option<Event>) -> Actor {
+        match event {
+            Some(Event::Audited { actor, .. }) => actor,
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    /// Logs the event at a lower level, which leaves out the bodies
+    fn at_level(mut event: AuditEvent, level: &str) -> AuditEvent {
+        event.response_object = None;
+        if level == "Metadata" {
+            event.request_object = None;
+        }
+        event
+    }
+
+    #[test]
+    fn test_create() {
+        let mut log = AuditLog::default();
+        let event = log.revision(audit_event("create", configmap("10"), configmap("10")));
+        let actor = actor(event.clone());
+        assert_eq!(actor.user, "alice");
+        assert_eq!(actor.verb, "create");
+        assert_eq!(actor.user_agent, "kubectl/v1.29.0");
+        let (obj, deleted) = audited(event);
+        assert!(!deleted);
+        assert_eq!(obj.resource_version().as_deref(), Some("10"));
+    }
+
+    #[test]
+    fn test_update_and_patch() {
+        let mut log = AuditLog::default();
+        let (obj, deleted) =
+            audited(log.revision(audit_event("update", configmap("11"), configmap("11"))));
+        assert!(!deleted);
+        assert_eq!(obj.resource_version().as_deref(), Some("11"));
+        let patch = json!({"data": {"key": "12"}});
+        let (obj, deleted) = audited(log.revision(audit_event("patch", patch, configmap("12"))));
+        assert!(!deleted);
+        assert_eq!(obj.data["data"]["key"], "12");
+    }
+
+    #[test]
+    fn test_left_out() {
+        let mut log = AuditLog::default();
+        assert!(log
+            .revision(audit_event("get", Value::Null, configmap("10")))
+            .is_none());
+        let mut event = audit_event("update", configmap("11"), configmap("11"));
+        event.stage = "RequestReceived".to_owned();
+        assert!(log.revision(event).is_none());
+        let mut event = audit_event("update", configmap("11"), status());
+        event.response_status = Some(ResponseStatus { code: 409 });
+        assert!(log.revision(event).is_none());
+        let mut event = audit_event("update", configmap("11"), configmap("11"));
+        event.object_ref.subresource = "scale".to_owned();
+        assert!(log.revision(event).is_none());
+    }
+
+    #[test]
+    fn test_lower_levels() {
+        let mut log = AuditLog::default();
+        for level in ["Request", "Metadata"] {
+            let event = audit_event("update", configmap("11"), configmap("11"));
+            assert!(log.revision(at_level(event, level)).is_none());
+        }
+        // a delete needs no body to be recorded
+        let event = audit_event("delete", delete_options(), status());
+        let (obj, deleted) = audited(log.revision(at_level(event, "Metadata")));
+        assert!(deleted);
+        assert_eq!(obj.name_any(), "cm");
+    }
+
+    #[test]
+    fn test_impersonated_user() {
+        let mut log = AuditLog::default();
+        let mut event = audit_event("create", configmap("10"), configmap("10"));
+        event.impersonated_user = Some(UserInfo {
+            username: "system:serviceaccount:default:deployer".to_owned(),
+        });
+        assert_eq!(
+            actor(log.revision(event)).user,
+            "alice as system:serviceaccount:default:deployer"
+        );
+    }
+
+    #[test]
+    fn test_tombstone_of_last_revision() {
+        let mut log = AuditLog::default();
+        log.revision(audit_event("create", configmap("10"), configmap("10")));
+        let (obj, deleted) =
+            audited(log.revision(audit_event("delete", delete_options(), status())));
+        assert!(deleted);
+        assert_eq!(obj.types.as_ref().unwrap().kind, "ConfigMap");
+        assert_eq!(obj.resource_version().as_deref(), Some("10"));
+        assert_eq!(obj.data["data"]["key"], "10");
+        assert!(log.objects.is_empty());
+    }
+
+    #[test]
+    fn test_tombstone_of_object_ref() {
+        let mut log = AuditLog::default();
+        let (obj, deleted) =
+            audited(log.revision(audit_event("delete", delete_options(), status())));
+        assert!(deleted);
+        let types = obj.types.as_ref().unwrap();
+        assert_eq!(types.api_version, "v1");
+        assert_eq!(types.kind, "configmaps");
+        assert_eq!(obj.name_any(), "cm");
+        assert_eq!(obj.namespace().as_deref(), Some("default"));
+        assert_eq!(obj.uid().as_deref(), Some("1234"));
+        assert_eq!(obj.resource_version().as_deref(), Some("0"));
+    }
+}
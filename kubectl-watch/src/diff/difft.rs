@@ -15,12 +15,14 @@ use std::path::PathBuf;
 
 pub struct Difft {
     include_managed_fields: bool,
+    metadata_only: bool,
 }
 
 impl Difft {
-    pub fn new(include_managed_fields: bool) -> Self {
+    pub fn new(include_managed_fields: bool, metadata_only: bool) -> Self {
         Difft {
             include_managed_fields: include_managed_fields,
+            metadata_only,
         }
     }
 }
@@ -60,21 +62,33 @@ impl<'a> Diff<'a> for Difft {
     fn tui_diff(
         &mut self,
         pre: Option<&DynamicObject>,
-        cur: &DynamicObject,
+        cur: Option<&DynamicObject>,
     ) -> (Paragraph<'a>, Paragraph<'a>) {
         let mut p = pipeline::Pipeline::init();
         if !self.include_managed_fields {
             p.add_task(pipeline::exclude_managed_fields);
         }
+        if self.metadata_only {
+            p.add_task(pipeline::retain_diffable_metadata);
+        }
 
-        let mut r = dynamic_object::DynamicObject::from(cur);
-        let mut l_yaml = String::new();
-        let r_yaml = serde_yaml::to_string(&r).unwrap();
-        if !pre.is_none() {
-            let mut l = dynamic_object::DynamicObject::from(pre.unwrap());
-            l_yaml = serde_yaml::to_string(&l).unwrap();
-            p.process(&mut l, &mut r);
+        let mut l = pre.map(dynamic_object::DynamicObject::from);
+        let mut r = cur.map(dynamic_object::DynamicObject::from);
+        match (l.as_mut(), r.as_mut()) {
+            (Some(l), Some(r)) => p.process(l, r),
+            // a missing side, e.g. of a deleted object, stays empty
+            (Some(one), None) | (None, Some(one)) => {
+                let mut other = one.clone();
+                p.process(one, &mut other);
+            }
+            (None, None) => {}
         }
+        let l_yaml = l
+            .map(|l| serde_yaml::to_string(&l).unwrap())
+            .unwrap_or_default();
+        let r_yaml = r
+            .map(|r| serde_yaml::to_string(&r).unwrap())
+            .unwrap_or_default();
 
         // init delta args
         let (minus_file, plus_file) = persistent::tmp_store(l_yaml, r_yaml);
@@ -13,10 +13,13 @@ pub trait Diff<'a> {
     fn tui_diff(
         &mut self,
         pre: Option<&DynamicObject>,
-        cur: &DynamicObject,
+        cur: Option<&DynamicObject>,
     ) -> (Paragraph<'a>, Paragraph<'a>);
 }
 
 pub fn new<'a>(app: &options::App) -> Box<dyn Diff<'a>> {
-    Box::new(difft::Difft::new(app.include_managed_fields))
+    Box::new(difft::Difft::new(
+        app.include_managed_fields,
+        app.metadata_only,
+    ))
 }
@@ -42,3 +42,11 @@ pub fn exclude_types(l: &mut dynamic_object::DynamicObject, r: &mut dynamic_obje
     l.exclude_types();
     r.exclude_types();
 }
+
+pub fn retain_diffable_metadata(
+    l: &mut dynamic_object::DynamicObject,
+    r: &mut dynamic_object::DynamicObject,
+) {
+    l.retain_diffable_metadata();
+    r.retain_diffable_metadata();
+}
@@ -0,0 +1,176 @@
+use crate::kube::Event;
+use crate::persistent::{self, Change, Export};
+
+use anyhow::{Context, Result};
+use kube::api::DynamicObject;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Path which stands for stdin
+pub const STDIN: &str = "-";
+
+/// Opens a file, or stdin for `-`
+pub fn open(path: &str) -> Result<Box<dyn Read + Send>> {
+    Ok(if path == STDIN {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).with_context(|| format!("failed to open {:?}", path))?)
+    })
+}
+
+/// Reads a watch stream from a file or stdin: the output of `kubectl get -w -o json`
+/// with or without `--output-watch-events`, or newline delimited objects, lists or
+/// watch events.
+pub fn read(path: &str, export: Option<Export>) -> Result<Receiver<Event>> {
+    let reader = open(path)?;
+    let (tx, rx) = channel(32);
+    tokio::task::spawn_blocking(move || {
+        let values = serde_json::Deserializer::from_reader(BufReader::new(reader)).into_iter();
+        for value in values {
+            let value = match value {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::warn!(%error, "stopped reading, invalid json");
+                    return;
+                }
+            };
+            let event = match parse(value) {
+                Ok(Some(event)) => event,
+                Ok(None) => continue,
+                Err(error) => {
+                    tracing::warn!(%error, "skipping invalid object");
+                    continue;
+                }
+            };
+            match &event {
+                Event::Added(obj) => persistent::store_resource(&export, obj, Change::Added),
+                Event::Applied(obj) => persistent::store_resource(&export, obj, Change::Modified),
+                Event::Deleted(obj) => persistent::store_resource(&export, obj, Change::Deleted),
+                Event::Restarted(objs) => {
+                    for obj in objs {
+                        persistent::store_resource(&export, obj, Change::Added);
+                    }
+                }
+                _ => {}
+            }
+            if tx.blocking_send(event).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+fn parse(value: Value) -> serde_json::Result<Option<Event>> {
+    let event = match (value.get("type"), value.get("object")) {
+        (Some(Value::String(kind)), Some(object)) => {
+            let obj = serde_json::from_value::<DynamicObject>(object.clone())?;
+            match kind.as_str() {
+                "ADDED" => Event::Added(obj),
+                "MODIFIED" => Event::Applied(obj),
+                "DELETED" => Event::Deleted(obj),
+                "BOOKMARK" => return Ok(None),
+                _ => {
+                    tracing::warn!(?object, "skipping watch error");
+                    return Ok(None);
+                }
+            }
+        }
+        _ if value.get("items").is_some_and(Value::is_array) => {
+            let mut list = serde_json::from_value::<Vec<DynamicObject>>(value["items"].clone())?;
+            list.retain(persistent::has_revision);
+            return Ok(Some(Event::Restarted(list)));
+        }
+        _ => Event::Applied(serde_json::from_value::<DynamicObject>(value)?),
+    };
+    match &event {
+        Event::Added(obj) | Event::Applied(obj) | Event::Deleted(obj)
+            if !persistent::has_revision(obj) =>
+        {
+            Ok(None)
+        }
+        _ => Ok(Some(event)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::ResourceExt;
+    use serde_json::json;
+
+    fn configmap(rv: Option<&str>) -> Value {
+        json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {"name": "cm", "namespace": "default", "resourceVersion": rv},
+        })
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let event = parse(configmap(Some("10"))).unwrap();
+        assert!(matches!(event, Some(Event::Applied(obj)) if obj.name_any() == "cm"));
+    }
+
+    #[test]
+    fn test_parse_watch_event() {
+        let event = json!({"type": "ADDED", "object": configmap(Some("10"))});
+        assert!(matches!(parse(event).unwrap(), Some(Event::Added(_))));
+        let event = json!({"type": "MODIFIED", "object": configmap(Some("11"))});
+        assert!(matches!(parse(event).unwrap(), Some(Event::Applied(_))));
+        let event = json!({"type": "DELETED", "object": configmap(Some("12"))});
+        assert!(matches!(parse(event).unwrap(), Some(Event::Deleted(_))));
+        let event = json!({"type": "BOOKMARK", "object": configmap(Some("13"))});
+        assert!(parse(event).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let list = json!({
+            "apiVersion": "v1",
+            "kind": "List",
+            "items": [configmap(Some("10")), configmap(None)],
+        });
+        match parse(list).unwrap() {
+            Some(Event::Restarted(objs)) => {
+                assert_eq!(objs.len(), 1);
+                assert_eq!(objs[0].resource_version().as_deref(), Some("10"));
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(json!("cm")).is_err());
+        assert!(parse(json!({"type": "ADDED", "object": "cm"})).is_err());
+        assert!(parse(json!({"items": ["cm"]})).is_err());
+    }
+
+    #[test]
+    fn test_parse_without_resource_version() {
+        assert!(parse(configmap(None)).unwrap().is_none());
+        let event = json!({"type": "MODIFIED", "object": configmap(None)});
+        assert!(parse(event).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_repeated_resource_version() {
+        // parsing keeps no state, the same revision is read again and left to
+        // the outputs to tell apart
+        let event = json!({"type": "MODIFIED", "object": configmap(Some("10"))});
+        for _ in 0..2 {
+            match parse(event.clone()).unwrap() {
+                Some(Event::Applied(obj)) => {
+                    assert_eq!(obj.resource_version().as_deref(), Some("10"))
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+    }
+}
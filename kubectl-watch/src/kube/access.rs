@@ -0,0 +1,61 @@
+use crate::kube::apigroup::{ApiCapabilities, ApiResource};
+
+use anyhow::Result;
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::{
+    api::{Api, PostParams},
+    Client,
+};
+
+/// Verbs a watch needs on its resource
+pub const WATCH_VERBS: [&str; 2] = ["list", "watch"];
+
+/// Returns the verbs needed for watching which the resource doesn't support
+pub fn unsupported_verbs(caps: &ApiCapabilities) -> Vec<&'static str> {
+    WATCH_VERBS
+        .into_iter()
+        .filter(|verb| !caps.operations.iter().any(|op| op == verb))
+        .collect()
+}
+
+/// Asks the api-server which of the watch verbs the current user may not use on the
+/// resource in the namespace, all namespaces if `None`.
+pub async fn denied_verbs(
+    cli: &Client,
+    ar: &ApiResource,
+    namespace: Option<&str>,
+) -> Result<Vec<&'static str>> {
+    let api: Api<SelfSubjectAccessReview> = Api::all(cli.clone());
+    let reviews = WATCH_VERBS.into_iter().map(|verb| {
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some(ar.group.clone()),
+                    version: Some(ar.version.clone()),
+                    resource: Some(ar.plural.clone()),
+                    namespace: namespace.map(str::to_owned),
+                    verb: Some(verb.to_owned()),
+                    ..ResourceAttributes::default()
+                }),
+                ..SelfSubjectAccessReviewSpec::default()
+            },
+            ..SelfSubjectAccessReview::default()
+        };
+        let api = api.clone();
+        async move {
+            let review = api.create(&PostParams::default(), &review).await?;
+            let allowed = review.status.is_some_and(|status| status.allowed);
+            Ok::<_, kube::Error>((verb, allowed))
+        }
+    });
+    let mut denied = vec![];
+    for result in futures::future::join_all(reviews).await {
+        let (verb, allowed) = result?;
+        if !allowed {
+            denied.push(verb);
+        }
+    }
+    Ok(denied)
+}
@@ -1,3 +1,5 @@
+use crate::kube::cache::{self, DiscoveryCache};
+
 use itertools::Itertools;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::{
     APIGroup, APIResource, APIResourceList, APIVersions,
@@ -161,6 +163,15 @@ impl ApiGroup {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Returns the resources served at a particular version of this group.
+    pub fn versioned_resources(&self, version: &str) -> Vec<(ApiResource, ApiCapabilities)> {
+        self.data
+            .iter()
+            .find(|gvd| gvd.version == version)
+            .map(|gvd| gvd.resources.clone())
+            .unwrap_or_default()
+    }
 }
 
 impl AllResource for ApiGroup {
@@ -183,7 +194,11 @@ impl AllResource for ApiGroup {
 }
 
 impl ApiGroup {
-    pub(crate) async fn query_apis(client: &Client, g: APIGroup) -> Result<Self> {
+    pub(crate) async fn query_apis(
+        client: &Client,
+        cache: Option<&DiscoveryCache>,
+        g: APIGroup,
+    ) -> Result<Self> {
         tracing::debug!(name = g.name.as_str(), "Listing group versions");
         let key = g.name;
         if g.versions.is_empty() {
@@ -191,7 +206,12 @@ impl ApiGroup {
         }
         let mut data = vec![];
         for vers in &g.versions {
-            let resources = client.list_api_group_resources(&vers.group_version).await?;
+            let resources = cache::cached(
+                cache,
+                &cache::server_resources(&vers.group_version),
+                client.list_api_group_resources(&vers.group_version),
+            )
+            .await?;
             data.push(GroupVersionData::new(vers.version.clone(), resources)?);
         }
         let mut group = ApiGroup {
@@ -203,13 +223,22 @@ impl ApiGroup {
         Ok(group)
     }
 
-    pub(crate) async fn query_core(client: &Client, coreapis: APIVersions) -> Result<Self> {
+    pub(crate) async fn query_core(
+        client: &Client,
+        cache: Option<&DiscoveryCache>,
+        coreapis: APIVersions,
+    ) -> Result<Self> {
         let mut data = vec![];
         if coreapis.versions.is_empty() {
             panic!("{:?}", "empty api group");
         }
         for v in coreapis.versions {
-            let resources = client.list_core_api_resources(&v).await?;
+            let resources = cache::cached(
+                cache,
+                &cache::server_resources(&v),
+                client.list_core_api_resources(&v),
+            )
+            .await?;
             data.push(GroupVersionData::new(v, resources)?);
         }
         let mut group = ApiGroup {
@@ -226,3 +255,24 @@ impl ApiGroup {
             .sort_by_cached_key(|gvd| Reverse(Version::parse(gvd.version.as_str()).priority()))
     }
 }
+
+#[cfg(test)]
+impl ApiGroup {
+    /// A group serving the resource lists of its versions, the most preferred first
+    pub(crate) fn from_lists(name: &str, lists: Vec<APIResourceList>) -> Self {
+        let data = lists
+            .into_iter()
+            .map(|list| {
+                let gv: GroupVersion = list.group_version.parse().unwrap();
+                GroupVersionData::new(gv.version, list).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut group = ApiGroup {
+            name: name.to_owned(),
+            preferred: data.first().map(|gvd| gvd.version.clone()),
+            data,
+        };
+        group.sort_versions();
+        group
+    }
+}
@@ -0,0 +1,115 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// File of the served api groups, relative to the cache directory of a cluster
+pub const SERVER_GROUPS: &str = "servergroups.json";
+
+/// File of the resources served by a group version, relative to the cache directory of a cluster
+pub fn server_resources(group_version: &str) -> String {
+    format!("{}/serverresources.json", group_version)
+}
+
+/// On-disk cache of discovery responses, laid out like kubectl's `~/.kube/cache/discovery`
+pub struct DiscoveryCache {
+    dir: PathBuf,
+    ttl: Duration,
+    /// Ignore cached data, responses are still written back
+    refresh: bool,
+}
+
+impl DiscoveryCache {
+    pub fn new(cache_dir: &Path, cluster_url: &str, ttl: Duration, refresh: bool) -> Self {
+        let mut dir = cache_dir.join("discovery");
+        dir.push(host_dir(cluster_url));
+        DiscoveryCache { dir, ttl, refresh }
+    }
+
+    /// Default cache directory, `~/.kube/cache`
+    pub fn default_dir() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        Some(PathBuf::from(home).join(".kube").join("cache"))
+    }
+
+    pub fn refresh(&mut self) {
+        self.refresh = true;
+    }
+
+    /// Returns the cached value if present and younger than the ttl
+    pub fn load<T: DeserializeOwned>(&self, path: &str) -> Option<T> {
+        if self.refresh {
+            return None;
+        }
+        let path = self.dir.join(path);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age > self.ttl {
+            return None;
+        }
+        let data = fs::read(&path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(value) => Some(value),
+            Err(error) => {
+                tracing::debug!(?path, %error, "ignoring invalid discovery cache");
+                None
+            }
+        }
+    }
+
+    pub fn store<T: Serialize>(&self, path: &str, value: &T) {
+        let path = self.dir.join(path);
+        if let Err(error) = write_atomic(&path, value) {
+            tracing::debug!(?path, %error, "failed to write discovery cache");
+        }
+    }
+}
+
+/// Loads a discovery response from the cache, or fetches it and fills the cache
+pub async fn cached<T, F>(cache: Option<&DiscoveryCache>, path: &str, fetch: F) -> kube::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = kube::Result<T>>,
+{
+    if let Some(value) = cache.and_then(|c| c.load(path)) {
+        return Ok(value);
+    }
+    let value = fetch.await?;
+    if let Some(cache) = cache {
+        cache.store(path, &value);
+    }
+    Ok(value)
+}
+
+pub(crate) fn write_atomic<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // other processes may read the file concurrently
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Directory name of a cluster, the same as kubectl computes it: the
+/// schemeless host with every character other than `\w`, `.`, `/`, `(` and `)`
+/// replaced by `_`.
+fn host_dir(cluster_url: &str) -> String {
+    let host = cluster_url.trim_end_matches('/');
+    let host = host
+        .strip_prefix("https://")
+        .or_else(|| host.strip_prefix("http://"))
+        .unwrap_or(host);
+    host.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "_./()".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
@@ -1,13 +1,99 @@
-use anyhow::Result;
-use kube::{Client, Config, Error};
-use std::convert::TryFrom;
-
-pub async fn client(use_tls: bool) -> Result<Client, Error> {
-    // init kube client
-    let mut config = Config::infer().await.map_err(Error::InferConfig)?;
-    if !use_tls {
+use crate::options;
+
+use anyhow::{anyhow, Context, Result};
+use kube::{
+    config::{KubeConfigOptions, Kubeconfig},
+    Config,
+};
+use std::fs;
+
+pub async fn config(app: &options::Connection) -> Result<Config> {
+    let options = KubeConfigOptions {
+        context: app.context.clone(),
+        cluster: app.cluster.clone(),
+        user: app.user.clone(),
+    };
+    let mut config = if let Some(path) = &app.kubeconfig {
+        let kubeconfig = Kubeconfig::read_from(path)?;
+        Config::from_custom_kubeconfig(kubeconfig, &options).await?
+    } else if options.context.is_some() || options.cluster.is_some() || options.user.is_some() {
+        Config::from_kubeconfig(&options).await?
+    } else {
+        Config::infer().await?
+    };
+    if app.insecure_skip_tls_verify {
         config.accept_invalid_certs = true;
     }
+    if let Some(path) = &app.certificate_authority {
+        let data = fs::read(path)
+            .with_context(|| format!("failed to read certificate authority {:?}", path))?;
+        config.root_cert = Some(
+            certs(&data)
+                .with_context(|| format!("failed to parse certificate authority {:?}", path))?,
+        );
+    }
+
+    if let Some(user) = &app.impersonate {
+        config.auth_info.impersonate = Some(user.clone());
+    }
+    if !app.impersonate_groups.is_empty() {
+        config.auth_info.impersonate_groups = Some(app.impersonate_groups.clone());
+    }
+
+    if let Some(timeout) = app.request_timeout.filter(|t| !t.is_zero()) {
+        config.connect_timeout = Some(timeout);
+        config.read_timeout = Some(timeout);
+        config.write_timeout = Some(timeout);
+    }
+
+    Ok(config)
+}
+
+/// Name of the kubeconfig context the config is built from, if any
+pub fn context_name(app: &options::Connection) -> Option<String> {
+    if app.context.is_some() {
+        return app.context.clone();
+    }
+    let kubeconfig = match &app.kubeconfig {
+        Some(path) => Kubeconfig::read_from(path),
+        None => Kubeconfig::read(),
+    };
+    kubeconfig.ok()?.current_context
+}
+
+/// Parses the DER encoded certificates of a PEM bundle
+fn certs(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let certs = pem::parse_many(data)?
+        .into_iter()
+        .filter(|p| p.tag == "CERTIFICATE")
+        .map(|p| p.contents)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate found"));
+    }
+    Ok(certs)
+}
+
+/// Explains a failed verification of the api-server certificate, other errors are returned as is
+pub fn explain_tls_error(error: anyhow::Error) -> anyhow::Error {
+    match tls_cause(&error) {
+        Some(cause) => error.context(format!(
+            "failed to verify the api-server certificate ({}), \
+             pass its CA with --certificate-authority or skip the verification with --insecure-skip-tls-verify",
+            cause
+        )),
+        None => error,
+    }
+}
+
+/// Whether an error is a failed verification of the api-server certificate
+pub fn is_tls_error(error: &anyhow::Error) -> bool {
+    tls_cause(error).is_some()
+}
 
-    Client::try_from(config)
+fn tls_cause(error: &anyhow::Error) -> Option<String> {
+    error
+        .chain()
+        .map(|e| e.to_string())
+        .find(|msg| msg.contains("invalid peer certificate"))
 }
@@ -1,15 +1,30 @@
 use crate::kube::apigroup::{AllResource, ApiCapabilities, ApiGroup, ApiResource};
+use crate::kube::cache::{self, DiscoveryCache};
+use crate::kube::{access, client};
+use crate::options;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use futures::StreamExt;
+use itertools::Itertools;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{
+    APIGroup, APIGroupList, APIVersions, GroupVersionForDiscovery,
+};
 use kube::{
     api::{Api, DynamicObject},
+    core::{gvk::GroupVersion, Version},
     discovery::Scope,
     Client,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+/// Number of api groups queried at the same time
+const DISCOVERY_CONCURRENCY: usize = 16;
+/// How long cached api discovery stays valid by default
+const DISCOVERY_CACHE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// Time after which an api group that doesn't answer is skipped
+const GROUP_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[allow(dead_code)]
-enum DiscoveryMode {
+pub enum DiscoveryMode {
     /// Only allow explicitly listed apigroups
     Allow(Vec<String>),
     /// Allow all apigroups except the ones listed
@@ -29,19 +44,44 @@ pub struct Discovery {
     client: Client,
     groups: HashMap<String, ApiGroup>,
     mode: DiscoveryMode,
+    cache: Option<DiscoveryCache>,
+    /// Whether the served groups were read from the cache
+    cached: bool,
 }
 
 impl Discovery {
     /// Construct a caching api discovery client
     #[must_use]
-    pub fn new(client: Client) -> Self {
+    pub fn new(client: Client, cache: Option<DiscoveryCache>) -> Self {
         let groups = HashMap::new();
         let mode = DiscoveryMode::Block(vec![]);
         Self {
             client,
             groups,
             mode,
+            cache,
+            cached: false,
+        }
+    }
+
+    /// Restrict which api groups are queried
+    #[must_use]
+    pub fn mode(mut self, mode: DiscoveryMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether the discovered data may be stale
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+
+    /// Query the api-server again, bypassing the cache
+    pub async fn refresh(mut self) -> Result<Self> {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.refresh();
         }
+        self.run().await
     }
 
     /// Returns iterator over all served groups
@@ -51,72 +91,428 @@ impl Discovery {
 
     pub async fn run(mut self) -> Result<Self> {
         self.groups.clear();
-        let api_groups = self.client.list_api_groups().await?;
-        // query regular groups + crds under /apis
-        for g in api_groups.groups {
-            let key = g.name.clone();
-            if self.mode.is_queryable(&key) {
-                let apigroup = ApiGroup::query_apis(&self.client, g).await?;
-                self.groups.insert(key, apigroup);
+        let (api_groups, coreapis) = self.server_groups().await?;
+        let cache = self.cache.as_ref();
+        let client = &self.client;
+        // query regular groups + crds under /apis concurrently, a broken aggregated
+        // api must not fail or stall the others
+        let queries = api_groups
+            .groups
+            .into_iter()
+            .filter(|g| self.mode.is_queryable(&g.name))
+            .map(|g| async move {
+                let key = g.name.clone();
+                let query = ApiGroup::query_apis(client, cache, g);
+                (key, tokio::time::timeout(GROUP_TIMEOUT, query).await)
+            });
+        let results = futures::stream::iter(queries)
+            .buffer_unordered(DISCOVERY_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await;
+        for (key, result) in results {
+            match result {
+                Ok(Ok(apigroup)) => {
+                    self.groups.insert(key, apigroup);
+                }
+                Ok(Err(error)) => tracing::warn!(group = key, %error, "skipping api group"),
+                Err(_) => tracing::warn!(group = key, "skipping api group, discovery timed out"),
             }
         }
         // query core versions under /api
         let corekey = ApiGroup::CORE_GROUP.to_string();
         if self.mode.is_queryable(&corekey) {
-            let coreapis = self.client.list_core_api_versions().await?;
-            let apigroup = ApiGroup::query_core(&self.client, coreapis).await?;
+            let apigroup = ApiGroup::query_core(&self.client, cache, coreapis).await?;
             self.groups.insert(corekey, apigroup);
         }
         Ok(self)
     }
+
+    /// Lists the served groups under /apis and the core versions under /api.
+    ///
+    /// Both are cached together like kubectl does, with the core group first.
+    async fn server_groups(&mut self) -> Result<(APIGroupList, APIVersions)> {
+        if let Some(mut groups) = self
+            .cache
+            .as_ref()
+            .and_then(|c| c.load::<APIGroupList>(cache::SERVER_GROUPS))
+        {
+            if let Some(pos) = groups
+                .groups
+                .iter()
+                .position(|g| g.name == ApiGroup::CORE_GROUP)
+            {
+                let core = groups.groups.remove(pos);
+                let coreapis = APIVersions {
+                    versions: core.versions.into_iter().map(|v| v.version).collect(),
+                    ..APIVersions::default()
+                };
+                self.cached = true;
+                return Ok((groups, coreapis));
+            }
+        }
+
+        self.cached = false;
+        let groups = self.client.list_api_groups().await?;
+        let coreapis = self.client.list_core_api_versions().await?;
+        if let Some(cache) = &self.cache {
+            let versions = coreapis
+                .versions
+                .iter()
+                .map(|v| GroupVersionForDiscovery {
+                    group_version: v.clone(),
+                    version: v.clone(),
+                })
+                .collect::<Vec<_>>();
+            let core = APIGroup {
+                name: ApiGroup::CORE_GROUP.to_string(),
+                preferred_version: versions.first().cloned(),
+                versions,
+                ..APIGroup::default()
+            };
+            let mut all = groups.clone();
+            all.groups.insert(0, core);
+            cache.store(cache::SERVER_GROUPS, &all);
+        }
+        Ok((groups, coreapis))
+    }
 }
 
-pub fn resolve_api_resource(
-    discovery: &Discovery,
-    name: &str,
-) -> Option<(ApiResource, ApiCapabilities)> {
-    // iterate through groups to find matching kind/plural names at recommended versions
-    // and then take the minimal match by group.name (equivalent to sorting groups by group.name).
-    // this is equivalent to kubectl's api group preference
+/// Resource name alias for every watchable resource
+pub const ALL_RESOURCES: &str = "all";
+
+/// Returns every resource at its recommended version which supports `list` and `watch`
+pub fn watchable_resources(discovery: &Discovery) -> Vec<(ApiResource, ApiCapabilities)> {
     discovery
         .groups()
-        .flat_map(|group| {
-            group
-                .recommended_resources()
-                .into_iter()
-                .map(move |res| (group, res))
-        })
-        .filter(|(_, (res, _))| {
-            let is_in_short_names = if let Some(short_names) = &res.short_names.clone() {
-                short_names.contains(&name.to_owned())
-            } else {
-                false
-            };
-            name.eq_ignore_ascii_case(&res.kind)
-                || name.eq_ignore_ascii_case(&res.plural)
-                || is_in_short_names
-        })
-        .min_by_key(|(group, _res)| group.name())
-        .map(|(_, res)| res)
+        .flat_map(|group| group.recommended_resources())
+        .filter(|(_, caps)| access::unsupported_verbs(caps).is_empty())
+        .collect()
+}
+
+/// Interpretations of a resource argument as kubectl accepts it: `name`, `name.group`
+/// or `name.version.group`, the most specific first.
+fn parse_resource_arg(arg: &str) -> Vec<(&str, Option<&str>, Option<&str>)> {
+    let (name, rest) = match arg.split_once('.') {
+        Some(parts) => parts,
+        None => return vec![(arg, None, None)],
+    };
+    let mut interpretations = vec![];
+    if let Some((version, group)) = rest.split_once('.') {
+        if Version::parse(version) != Version::Nonconformant(version.to_owned()) {
+            interpretations.push((name, Some(version), Some(group)));
+        }
+    }
+    interpretations.push((name, None, Some(rest)));
+    interpretations
+}
+
+/// Resolves a resource argument, optionally pinned to a group version.
+///
+/// Returns `None` if nothing matches, and an error listing every candidate when a
+/// bare name matches resources of several groups.
+pub fn resolve_api_resource(
+    discovery: &Discovery,
+    arg: &str,
+    api_version: Option<&GroupVersion>,
+) -> Result<Option<(ApiResource, ApiCapabilities)>> {
+    let interpretations = match api_version {
+        Some(gv) if !arg.contains('.') => {
+            vec![(arg, Some(gv.version.as_str()), Some(gv.group.as_str()))]
+        }
+        _ => parse_resource_arg(arg),
+    };
+    for (name, version, group) in interpretations {
+        // iterate through groups to find matching kind/plural names at the requested
+        // version, or the recommended one
+        let candidates = discovery
+            .groups()
+            .filter(|g| match group {
+                Some(group) => g.name() == group,
+                None => true,
+            })
+            .flat_map(|g| {
+                let resources = match version {
+                    Some(version) => g.versioned_resources(version),
+                    None => g.recommended_resources(),
+                };
+                resources.into_iter().map(move |res| (g.name(), res))
+            })
+            .filter(|(_, (res, _))| {
+                let is_in_short_names = if let Some(short_names) = &res.short_names.clone() {
+                    short_names.contains(&name.to_owned())
+                } else {
+                    false
+                };
+                name.eq_ignore_ascii_case(&res.kind)
+                    || name.eq_ignore_ascii_case(&res.plural)
+                    || is_in_short_names
+            })
+            .collect::<Vec<_>>();
+
+        // like kubectl, the core group takes precedence over any other
+        if let Some((_, res)) = candidates
+            .iter()
+            .find(|(group, _)| *group == ApiGroup::CORE_GROUP)
+        {
+            return Ok(Some(res.clone()));
+        }
+        let groups = candidates
+            .iter()
+            .map(|(group, _)| *group)
+            .unique()
+            .collect::<Vec<_>>();
+        if groups.len() > 1 {
+            let names = candidates
+                .iter()
+                .map(|(group, (res, _))| format!("{}.{}.{}", res.plural, res.version, group))
+                .join(", ");
+            bail!(
+                "resource {:?} is ambiguous, it matches {}; qualify it with its group or pass --api-version",
+                arg,
+                names
+            );
+        }
+        if let Some((_, res)) = candidates.into_iter().next() {
+            return Ok(Some(res));
+        }
+    }
+    Ok(None)
 }
 
+/// Returns the api of a resource in the namespace, all namespaces if `None`
 pub fn dynamic_api(
     ar: ApiResource,
     caps: ApiCapabilities,
     client: Client,
-    ns: &Option<String>,
-    all: bool,
+    namespace: Option<&str>,
 ) -> Api<DynamicObject> {
-    if caps.scope == Scope::Cluster || all {
-        Api::all_with(client, &ar.to_kube_ar())
-    } else if let Some(namespace) = ns {
-        Api::namespaced_with(client, namespace, &ar.to_kube_ar())
-    } else {
-        Api::default_namespaced_with(client, &ar.to_kube_ar())
+    match namespace {
+        Some(namespace) if caps.scope == Scope::Namespaced => {
+            Api::namespaced_with(client, namespace, &ar.to_kube_ar())
+        }
+        _ => Api::all_with(client, &ar.to_kube_ar()),
     }
 }
 
-pub async fn new(cli: &Client) -> Result<Discovery> {
-    let discovery = Discovery::new(cli.clone()).run().await?;
+pub async fn new(
+    cli: &Client,
+    cache: Option<DiscoveryCache>,
+    mode: DiscoveryMode,
+) -> Result<Discovery> {
+    let discovery = Discovery::new(cli.clone(), cache).mode(mode).run().await?;
     Ok(discovery)
 }
+
+/// Discovers the api groups of the cluster as configured on the command line, or only
+/// `groups` if given.
+pub async fn discover(
+    app: &options::Connection,
+    cli: &Client,
+    cluster_url: &str,
+    groups: Option<Vec<String>>,
+) -> Result<Discovery> {
+    let cache_dir = match &app.cache_dir {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => DiscoveryCache::default_dir(),
+    };
+    let cache = cache_dir.map(|dir| {
+        DiscoveryCache::new(
+            &dir,
+            cluster_url,
+            app.discovery_cache_ttl.unwrap_or(DISCOVERY_CACHE_TTL),
+            app.refresh_discovery,
+        )
+    });
+    let group_names = |groups: &Vec<String>| {
+        groups
+            .iter()
+            .map(|g| {
+                if g == "core" {
+                    String::new()
+                } else {
+                    g.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let mode = match groups {
+        Some(groups) => DiscoveryMode::Allow(groups),
+        None if !app.discovery_allow_groups.is_empty() => {
+            DiscoveryMode::Allow(group_names(&app.discovery_allow_groups))
+        }
+        None => DiscoveryMode::Block(group_names(&app.discovery_block_groups)),
+    };
+    new(cli, cache, mode)
+        .await
+        .map_err(client::explain_tls_error)
+}
+
+/// Returns the api groups a qualified resource name like `certificates.cert-manager.io`
+/// or `deployments.v1.apps` may refer to, `None` for a bare name.
+pub fn groups_of(name: &str) -> Option<Vec<String>> {
+    let groups = parse_resource_arg(name)
+        .into_iter()
+        .filter_map(|(_, _, group)| group.map(str::to_owned))
+        .collect::<Vec<_>>();
+    if groups.is_empty() {
+        None
+    } else {
+        Some(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::{APIResource, APIResourceList};
+    use kube::Config;
+
+    use super::*;
+
+    fn list(group_version: &str, resources: &[(&str, &str, &[&str])]) -> APIResourceList {
+        APIResourceList {
+            group_version: group_version.to_owned(),
+            resources: resources
+                .iter()
+                .map(|(kind, plural, short_names)| APIResource {
+                    kind: kind.to_string(),
+                    name: plural.to_string(),
+                    namespaced: true,
+                    short_names: Some(short_names.iter().map(|s| s.to_string()).collect()),
+                    verbs: vec!["list".to_owned(), "watch".to_owned()],
+                    ..APIResource::default()
+                })
+                .collect(),
+        }
+    }
+
+    /// A discovery of the core group, apps, and two groups serving certificates
+    fn discovery() -> Discovery {
+        let groups = [
+            ApiGroup::from_lists(
+                ApiGroup::CORE_GROUP,
+                vec![list("v1", &[("Pod", "pods", &["po"])])],
+            ),
+            ApiGroup::from_lists(
+                "apps",
+                vec![
+                    list("apps/v1", &[("Deployment", "deployments", &["deploy"])]),
+                    list(
+                        "apps/v1beta1",
+                        &[("Deployment", "deployments", &["deploy"])],
+                    ),
+                ],
+            ),
+            ApiGroup::from_lists(
+                "cert-manager.io",
+                vec![list(
+                    "cert-manager.io/v1",
+                    &[("Certificate", "certificates", &["cert"])],
+                )],
+            ),
+            ApiGroup::from_lists(
+                "networking.gke.io",
+                vec![list(
+                    "networking.gke.io/v1",
+                    &[("Certificate", "certificates", &["mcrt"])],
+                )],
+            ),
+        ];
+        let config = Config::new("http://127.0.0.1:1".parse().unwrap());
+        Discovery {
+            client: Client::try_from(config).unwrap(),
+            groups: groups
+                .into_iter()
+                .map(|g| (g.name().to_owned(), g))
+                .collect(),
+            mode: DiscoveryMode::Block(vec![]),
+            cache: None,
+            cached: false,
+        }
+    }
+
+    fn resolve(arg: &str) -> Result<Option<(String, String, String)>> {
+        let resolved = resolve_api_resource(&discovery(), arg, None)?;
+        Ok(resolved.map(|(ar, _)| (ar.plural, ar.version, ar.group)))
+    }
+
+    #[test]
+    fn test_parse_resource_arg_name() {
+        assert_eq!(parse_resource_arg("pods"), vec![("pods", None, None)]);
+    }
+
+    #[test]
+    fn test_parse_resource_arg_name_group() {
+        assert_eq!(
+            parse_resource_arg("certificates.cert-manager.io"),
+            vec![("certificates", None, Some("cert-manager.io"))]
+        );
+    }
+
+    #[test]
+    fn test_parse_resource_arg_name_version_group() {
+        assert_eq!(
+            parse_resource_arg("deployments.v1.apps"),
+            vec![
+                ("deployments", Some("v1"), Some("apps")),
+                ("deployments", None, Some("v1.apps")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_name() {
+        assert_eq!(
+            resolve("deploy").unwrap(),
+            Some(("deployments".to_owned(), "v1".to_owned(), "apps".to_owned()))
+        );
+        assert_eq!(resolve("unknown").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_core_takes_precedence() {
+        assert_eq!(
+            resolve("Pod").unwrap(),
+            Some(("pods".to_owned(), "v1".to_owned(), String::new()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_name_group() {
+        assert_eq!(
+            resolve("certificates.cert-manager.io").unwrap(),
+            Some((
+                "certificates".to_owned(),
+                "v1".to_owned(),
+                "cert-manager.io".to_owned()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_name_version_group() {
+        assert_eq!(
+            resolve("deployments.v1beta1.apps").unwrap(),
+            Some((
+                "deployments".to_owned(),
+                "v1beta1".to_owned(),
+                "apps".to_owned()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ambiguous_name() {
+        let error = resolve("certificates").unwrap_err().to_string();
+        assert!(error.contains("ambiguous"), "{}", error);
+        assert!(
+            error.contains("certificates.v1.cert-manager.io"),
+            "{}",
+            error
+        );
+        assert!(
+            error.contains("certificates.v1.networking.gke.io"),
+            "{}",
+            error
+        );
+    }
+}
@@ -30,4 +30,21 @@ impl DynamicObject {
     pub fn exclude_managed_fields(&mut self) {
         self.metadata.managed_fields = None;
     }
+
+    /// Keeps the name and what a metadata-only watch diffs: labels, annotations,
+    /// ownerReferences, finalizers and generation
+    pub fn retain_diffable_metadata(&mut self) {
+        let metadata = std::mem::take(&mut self.metadata);
+        self.metadata = ObjectMeta {
+            name: metadata.name,
+            namespace: metadata.namespace,
+            labels: metadata.labels,
+            annotations: metadata.annotations,
+            owner_references: metadata.owner_references,
+            finalizers: metadata.finalizers,
+            generation: metadata.generation,
+            ..ObjectMeta::default()
+        };
+        self.data = serde_json::Value::Object(serde_json::Map::new());
+    }
 }
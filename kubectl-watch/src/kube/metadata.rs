@@ -0,0 +1,70 @@
+use crate::kube::watcher::send_initial_events;
+
+use futures::{stream::BoxStream, StreamExt};
+use http::header::{HeaderValue, ACCEPT};
+use kube::{
+    api::{DynamicObject, ListParams, ObjectList, WatchEvent},
+    core::{ApiResource, Request},
+    Client, Error, Resource,
+};
+
+/// Asks the api-server for `PartialObjectMetadataList` instead of full objects
+const LIST_ACCEPT: &str =
+    "application/json;as=PartialObjectMetadataList;g=meta.k8s.io;v=v1,application/json";
+/// Asks the api-server for `PartialObjectMetadata` instead of full objects
+const WATCH_ACCEPT: &str =
+    "application/json;as=PartialObjectMetadata;g=meta.k8s.io;v=v1,application/json";
+
+/// Lists and watches only the metadata of objects, the bodies are never sent.
+///
+/// Objects come back as `DynamicObject`s without any data besides their metadata.
+#[derive(Clone)]
+pub struct MetadataApi {
+    client: Client,
+    request: Request,
+}
+
+impl MetadataApi {
+    /// Metadata api of a resource in the namespace, all namespaces if `None`
+    pub fn new(client: Client, ar: &ApiResource, namespace: Option<&str>) -> Self {
+        let url = DynamicObject::url_path(ar, namespace);
+        MetadataApi {
+            client,
+            request: Request::new(url),
+        }
+    }
+
+    pub async fn list(&self, lp: &ListParams) -> kube::Result<ObjectList<DynamicObject>> {
+        let mut req = self.request.list(lp).map_err(Error::BuildRequest)?;
+        req.headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static(LIST_ACCEPT));
+        self.client.request(req).await
+    }
+
+    pub async fn watch(
+        &self,
+        lp: &ListParams,
+        version: &str,
+    ) -> kube::Result<BoxStream<'static, kube::Result<WatchEvent<DynamicObject>>>> {
+        let mut req = self
+            .request
+            .watch(lp, version)
+            .map_err(Error::BuildRequest)?;
+        req.headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static(WATCH_ACCEPT));
+        let stream = self.client.request_events(req).await?;
+        Ok(stream.boxed())
+    }
+
+    /// Watches starting with the current state as added events
+    pub async fn watch_initial(
+        &self,
+        lp: &ListParams,
+    ) -> kube::Result<BoxStream<'static, kube::Result<WatchEvent<DynamicObject>>>> {
+        let mut req = self.request.watch(lp, "").map_err(Error::BuildRequest)?;
+        req.headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static(WATCH_ACCEPT));
+        let stream = self.client.request_events(send_initial_events(req)).await?;
+        Ok(stream.boxed())
+    }
+}
@@ -1,10 +1,20 @@
+mod access;
 mod apigroup;
+mod cache;
 pub mod client;
 mod discovery;
 pub mod dynamic_object;
+mod metadata;
+mod namespaces;
+mod owners;
+mod resources;
+mod resume;
 mod watch;
+mod watcher;
 
-pub use client::client;
+pub use apigroup::{ApiCapabilities, ApiResource};
 pub use discovery::new;
 pub use discovery::{dynamic_api, resolve_api_resource};
+pub use resources::api_resources;
 pub use watch::watch;
+pub use watcher::{Actor, ConnectionState, Event};
@@ -0,0 +1,158 @@
+use crate::kube::Event;
+
+use anyhow::{Context, Result};
+use kube::api::ResourceExt;
+use regex::Regex;
+
+/// Whether a namespace argument is a glob like `team-*` or a regex like `/^team-\d+$/`
+/// rather than a plain name.
+pub fn is_pattern(arg: &str) -> bool {
+    arg.contains(['*', '?', '[']) || is_regex(arg)
+}
+
+fn is_regex(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('/') && arg.ends_with('/')
+}
+
+/// Compiles a namespace name, glob or `/regex/` into a regex matching whole names
+fn compile(arg: &str) -> Result<Regex> {
+    let pattern = if is_regex(arg) {
+        arg[1..arg.len() - 1].to_owned()
+    } else {
+        let mut pattern = "^".to_owned();
+        for c in arg.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                // character classes keep their glob meaning
+                '[' | ']' => pattern.push(c),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        pattern
+    };
+    Regex::new(&pattern).with_context(|| format!("invalid namespace pattern {:?}", arg))
+}
+
+/// Client-side namespace filter for watches over all namespaces
+pub struct NamespaceFilter {
+    /// Matches any namespace if empty
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl NamespaceFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(NamespaceFilter {
+            include: include
+                .iter()
+                .map(|arg| compile(arg))
+                .collect::<Result<_>>()?,
+            exclude: exclude
+                .iter()
+                .map(|arg| compile(arg))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Whether an object in the namespace passes, cluster scoped objects always do
+    pub fn matches(&self, namespace: Option<&str>) -> bool {
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => return true,
+        };
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(namespace)))
+            && !self.exclude.iter().any(|re| re.is_match(namespace))
+    }
+
+    pub fn filter(&self, event: Event) -> Option<Event> {
+        match event {
+            Event::Added(obj) | Event::Applied(obj) | Event::Deleted(obj)
+                if !self.matches(obj.namespace().as_deref()) =>
+            {
+                None
+            }
+            Event::Restarted(mut objs) => {
+                objs.retain(|obj| self.matches(obj.namespace().as_deref()));
+                Some(Event::Restarted(objs))
+            }
+            event => Some(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_pattern() {
+        assert!(!is_pattern("default"));
+        assert!(is_pattern("team-*"));
+        assert!(is_pattern("team-[ab]"));
+        assert!(is_pattern("/^team-\\d+$/"));
+        assert!(!is_pattern("/"));
+    }
+
+    #[test]
+    fn test_compile_name() {
+        let re = compile("kube-system").unwrap();
+        assert!(re.is_match("kube-system"));
+        assert!(!re.is_match("kube-system-2"));
+        assert!(!re.is_match("my-kube-system"));
+    }
+
+    #[test]
+    fn test_compile_glob() {
+        let re = compile("team-?-*").unwrap();
+        assert!(re.is_match("team-a-dev"));
+        assert!(re.is_match("team-b-"));
+        assert!(!re.is_match("team-ab-dev"));
+        assert!(!re.is_match("my-team-a-dev"));
+    }
+
+    #[test]
+    fn test_compile_glob_escapes_regex() {
+        let re = compile("team.a").unwrap();
+        assert!(re.is_match("team.a"));
+        assert!(!re.is_match("teamxa"));
+    }
+
+    #[test]
+    fn test_compile_character_class() {
+        let re = compile("team-[ab]").unwrap();
+        assert!(re.is_match("team-a"));
+        assert!(re.is_match("team-b"));
+        assert!(!re.is_match("team-c"));
+        assert!(!re.is_match("team-[ab]"));
+    }
+
+    #[test]
+    fn test_compile_unclosed_character_class() {
+        assert!(compile("team-[ab").is_err());
+    }
+
+    #[test]
+    fn test_compile_regex() {
+        let re = compile("/^team-\\d+$/").unwrap();
+        assert!(re.is_match("team-42"));
+        assert!(!re.is_match("team-a"));
+    }
+
+    #[test]
+    fn test_compile_regex_unanchored() {
+        let re = compile("/prod/").unwrap();
+        assert!(re.is_match("shop-prod-eu"));
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let filter =
+            NamespaceFilter::new(&["team-*".to_owned()], &["team-tmp".to_owned()]).unwrap();
+        assert!(filter.matches(Some("team-a")));
+        assert!(!filter.matches(Some("team-tmp")));
+        assert!(!filter.matches(Some("default")));
+        assert!(filter.matches(None));
+    }
+}
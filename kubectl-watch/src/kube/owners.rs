@@ -0,0 +1,216 @@
+use crate::kube::Event;
+
+use kube::api::{DynamicObject, ResourceExt};
+use std::collections::{HashMap, HashSet};
+
+/// Keeps the ownership tree of a root object, built from `metadata.ownerReferences`,
+/// and filters watch events down to the objects in that tree.
+#[derive(Clone)]
+pub struct OwnerTree {
+    kind: String,
+    name: String,
+    /// Namespace of the root, `None` for a cluster scoped root
+    namespace: Option<String>,
+    /// Uids of the root and all of its descendants seen so far
+    members: HashSet<String>,
+    /// Owners of the objects not (yet) known to be in the tree, keyed by uid. Objects
+    /// without owners can't join the tree and aren't kept.
+    orphans: HashMap<String, Vec<String>>,
+}
+
+impl OwnerTree {
+    pub fn new(kind: String, name: String, namespace: Option<String>) -> Self {
+        OwnerTree {
+            kind,
+            name,
+            namespace,
+            members: HashSet::new(),
+            orphans: HashMap::new(),
+        }
+    }
+
+    pub fn filter(&mut self, event: Event) -> Vec<Event> {
+        match event {
+            Event::Added(obj) if self.admit(&obj) => vec![Event::Added(obj)],
+            Event::Applied(obj) if self.admit(&obj) => vec![Event::Applied(obj)],
+            Event::Added(_) | Event::Applied(_) => vec![],
+            Event::Deleted(obj) => {
+                let uid = obj.uid().unwrap_or_default();
+                self.orphans.remove(&uid);
+                if self.members.remove(&uid) {
+                    vec![Event::Deleted(obj)]
+                } else {
+                    vec![]
+                }
+            }
+            Event::Restarted(mut objs) => {
+                objs.retain(|obj| self.admit(obj));
+                vec![Event::Restarted(objs)]
+            }
+            event => vec![event],
+        }
+    }
+
+    /// Whether the object belongs to the tree, otherwise its owners are kept aside
+    /// until one of them joins. Orphans adopted that way show with their next event.
+    fn admit(&mut self, obj: &DynamicObject) -> bool {
+        let uid = obj.uid().unwrap_or_default();
+        let owners = owners(obj);
+        let is_member = self.members.contains(&uid)
+            || self.is_root(obj)
+            || owners.iter().any(|owner| self.members.contains(owner));
+        if !is_member {
+            if !owners.is_empty() {
+                self.orphans.insert(uid, owners);
+            }
+            return false;
+        }
+        self.adopt(uid);
+        true
+    }
+
+    /// Makes an object a member, along with the orphans it owns, transitively
+    fn adopt(&mut self, uid: String) {
+        self.orphans.remove(&uid);
+        let children = self
+            .orphans
+            .iter()
+            .filter(|(_, owners)| owners.contains(&uid))
+            .map(|(child, _)| child.clone())
+            .collect::<Vec<_>>();
+        self.members.insert(uid);
+        for child in children {
+            self.adopt(child);
+        }
+    }
+
+    fn is_root(&self, obj: &DynamicObject) -> bool {
+        obj.name_any() == self.name
+            && obj.namespace() == self.namespace
+            && obj
+                .types
+                .as_ref()
+                .is_some_and(|types| types.kind == self.kind)
+    }
+}
+
+fn owners(obj: &DynamicObject) -> Vec<String> {
+    obj.owner_references()
+        .iter()
+        .map(|owner| owner.uid.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(kind: &str, namespace: &str, name: &str, owners: &[&str]) -> DynamicObject {
+        let owners = owners
+            .iter()
+            .map(|uid| {
+                serde_json::json!({
+                    "apiVersion": "v1",
+                    "kind": "Owner",
+                    "name": uid,
+                    "uid": uid,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": kind,
+            "metadata": {
+                "name": name,
+                "namespace": namespace,
+                "uid": name,
+                "ownerReferences": owners,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn tree() -> OwnerTree {
+        OwnerTree::new(
+            "Deployment".to_owned(),
+            "web".to_owned(),
+            Some("default".to_owned()),
+        )
+    }
+
+    fn names(events: Vec<Event>) -> Vec<String> {
+        events
+            .into_iter()
+            .flat_map(|event| match event {
+                Event::Added(obj) | Event::Applied(obj) | Event::Deleted(obj) => vec![obj],
+                Event::Restarted(objs) => objs,
+                _ => vec![],
+            })
+            .map(|obj| obj.name_any())
+            .collect()
+    }
+
+    #[test]
+    fn test_root() {
+        let mut tree = tree();
+        let root = object("Deployment", "default", "web", &[]);
+        assert_eq!(names(tree.filter(Event::Added(root))), vec!["web"]);
+    }
+
+    #[test]
+    fn test_root_in_other_namespace() {
+        let mut tree = tree();
+        let other = object("Deployment", "staging", "web", &[]);
+        assert!(tree.filter(Event::Added(other)).is_empty());
+        let other_kind = object("Service", "default", "web", &[]);
+        assert!(tree.filter(Event::Added(other_kind)).is_empty());
+    }
+
+    #[test]
+    fn test_transitive_child() {
+        let mut tree = tree();
+        let events = tree.filter(Event::Restarted(vec![
+            object("Deployment", "default", "web", &[]),
+            object("ReplicaSet", "default", "web-1", &["web"]),
+            object("Pod", "default", "web-1-a", &["web-1"]),
+            object("Pod", "default", "db-0", &["db"]),
+        ]));
+        assert_eq!(names(events), vec!["web", "web-1", "web-1-a"]);
+    }
+
+    #[test]
+    fn test_orphan_adopted_later() {
+        let mut tree = tree();
+        let pod = object("Pod", "default", "web-1-a", &["web-1"]);
+        assert!(tree.filter(Event::Added(pod.clone())).is_empty());
+        let rs = object("ReplicaSet", "default", "web-1", &["web"]);
+        assert!(tree.filter(Event::Added(rs)).is_empty());
+
+        let root = object("Deployment", "default", "web", &[]);
+        assert_eq!(names(tree.filter(Event::Added(root))), vec!["web"]);
+        assert!(tree.orphans.is_empty());
+        assert_eq!(names(tree.filter(Event::Applied(pod))), vec!["web-1-a"]);
+    }
+
+    #[test]
+    fn test_unowned_objects_are_not_kept() {
+        let mut tree = tree();
+        let secret = object("Secret", "default", "token", &[]);
+        assert!(tree.filter(Event::Added(secret)).is_empty());
+        assert!(tree.orphans.is_empty());
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut tree = tree();
+        let root = object("Deployment", "default", "web", &[]);
+        tree.filter(Event::Added(root.clone()));
+        let orphan = object("Pod", "default", "db-0", &["db"]);
+        tree.filter(Event::Added(orphan.clone()));
+
+        assert!(tree.filter(Event::Deleted(orphan)).is_empty());
+        assert!(tree.orphans.is_empty());
+        assert_eq!(names(tree.filter(Event::Deleted(root))), vec!["web"]);
+        assert!(tree.members.is_empty());
+    }
+}
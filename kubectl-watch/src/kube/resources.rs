@@ -0,0 +1,31 @@
+use crate::kube::apigroup::{AllResource, ApiCapabilities, ApiResource};
+use crate::kube::{access, client, discovery};
+use crate::options;
+
+use anyhow::Result;
+use itertools::Itertools;
+use kube::{discovery::Scope, Client};
+
+/// Returns the resources found by discovery at their recommended version, sorted by
+/// group and name.
+pub async fn api_resources(
+    connection: &options::Connection,
+    args: &options::ApiResources,
+) -> Result<Vec<(ApiResource, ApiCapabilities)>> {
+    let config = client::config(connection).await?;
+    let cluster_url = config.cluster_url.to_string();
+    let cli = Client::try_from(config)?;
+    let discovery = discovery::discover(connection, &cli, &cluster_url, None).await?;
+
+    let resources = discovery
+        .groups()
+        .flat_map(|group| group.recommended_resources())
+        .filter(|(_, caps)| !args.watchable || access::unsupported_verbs(caps).is_empty())
+        .filter(|(_, caps)| match args.namespaced {
+            Some(namespaced) => namespaced == (caps.scope == Scope::Namespaced),
+            None => true,
+        })
+        .sorted_by(|(a, _), (b, _)| (&a.group, &a.plural).cmp(&(&b.group, &b.plural)))
+        .collect();
+    Ok(resources)
+}
@@ -0,0 +1,121 @@
+use crate::kube::cache::write_atomic;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// File of the resume state, relative to the export directory
+pub const STATE_FILE: &str = ".kubectl-watch-state.json";
+
+/// Minimum time between two writes of the state file
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Last seen resource version of every watch, persisted so a later run can resume
+/// the watches from there.
+///
+/// The file is written at most once per `WRITE_INTERVAL`, versions set in between are
+/// written at the end of the interval. A resumed watch may see a few events again but
+/// never misses one.
+#[derive(Clone)]
+pub struct ResumeState {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    path: PathBuf,
+    versions: BTreeMap<String, String>,
+    written: Option<Instant>,
+    /// A write is scheduled for the end of the interval
+    pending: bool,
+}
+
+impl ResumeState {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(STATE_FILE);
+        let versions = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|error| {
+                tracing::warn!(?path, %error, "ignoring invalid resume state");
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        ResumeState {
+            inner: Arc::new(Mutex::new(Inner {
+                path,
+                versions,
+                written: None,
+                pending: false,
+            })),
+        }
+    }
+
+    /// Resource version the watch stopped at
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.inner.lock().unwrap().versions.get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, resource_version: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.versions.get(key).map(String::as_str) == Some(resource_version) {
+            return;
+        }
+        inner
+            .versions
+            .insert(key.to_owned(), resource_version.to_owned());
+        if inner.pending {
+            return;
+        }
+        let elapsed = inner.written.map(|written| written.elapsed());
+        match elapsed {
+            Some(elapsed) if elapsed < WRITE_INTERVAL => {
+                inner.pending = true;
+                let state = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(WRITE_INTERVAL - elapsed).await;
+                    state.inner.lock().unwrap().write();
+                });
+            }
+            _ => inner.write(),
+        }
+    }
+}
+
+impl Inner {
+    fn write(&mut self) {
+        self.pending = false;
+        self.written = Some(Instant::now());
+        if let Err(error) = write_atomic(&self.path, &self.versions) {
+            tracing::warn!(path = ?self.path, %error, "failed to write resume state");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(dir: &Path) -> BTreeMap<String, String> {
+        serde_json::from_slice(&fs::read(dir.join(STATE_FILE)).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_trailing_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = ResumeState::load(dir.path());
+        state.set("v1/Pod/default", "10");
+        state.set("v1/Pod/default", "11");
+        state.set("v1/Pod/default", "12");
+        assert_eq!(saved(dir.path())["v1/Pod/default"], "10");
+
+        tokio::time::sleep(WRITE_INTERVAL + Duration::from_millis(200)).await;
+        assert_eq!(saved(dir.path())["v1/Pod/default"], "12");
+        assert_eq!(
+            ResumeState::load(dir.path()).get("v1/Pod/default").unwrap(),
+            "12"
+        );
+    }
+}
@@ -1,59 +1,456 @@
-use crate::kube::{client, discovery};
+use crate::kube::metadata::MetadataApi;
+use crate::kube::namespaces::{self, NamespaceFilter};
+use crate::kube::owners::OwnerTree;
+use crate::kube::resume::ResumeState;
+use crate::kube::watcher::{Event, Source, Watcher};
+use crate::kube::{access, client, discovery};
 use crate::options;
-use crate::persistent;
-
-use anyhow::{Context, Result};
-use futures::{StreamExt, TryStreamExt};
+use crate::persistent::{self, Change};
 
+use anyhow::{anyhow, bail, Context, Result};
+use futures::StreamExt;
+use itertools::Itertools;
 use kube::{
-    api::{DynamicObject, ListParams},
+    api::{DynamicObject, ListParams, ResourceExt},
+    core::{gvk::GroupVersion, TypeMeta},
     discovery::Scope,
-    runtime::{watcher, WatchStreamExt},
+    Client,
 };
+use std::path::Path;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-pub async fn watch(app: &options::App) -> Result<Receiver<DynamicObject>> {
-    let cli = client::client(app.use_tls).await?;
-    let discovery = discovery::new(&cli).await?;
-    let resource = app.resource.clone();
-    // Common discovery, parameters, and api configuration for a single resource
-    let (ar, caps) = discovery::resolve_api_resource(&discovery, resource.as_str())
-        .with_context(|| format!("resource {:?} not found in cluster", resource))?;
+/// Number of access reviews sent at the same time
+const REVIEW_CONCURRENCY: usize = 16;
+
+pub async fn watch(app: &options::App) -> Result<Receiver<Event>> {
+    let config = client::config(&app.connection).await?;
+    let cluster_url = config.cluster_url.to_string();
+    let export = persistent::open(app)?.map(|export| {
+        export.cluster(
+            client::context_name(&app.connection),
+            Some(cluster_url.clone()),
+        )
+    });
+    let default_namespace = config.default_namespace.clone();
+    let cli = Client::try_from(config)?;
+    // a `resource/name` argument selects the named object
+    let resource = app.resource.as_deref().unwrap_or_default();
+    let mut object_names = vec![];
+    let resource = match resource.split_once('/') {
+        Some((resource, name)) => {
+            object_names.push(name.to_owned());
+            resource.to_owned()
+        }
+        None => resource.to_owned(),
+    };
+    object_names.extend(app.names.iter().cloned());
+    let object_names = object_names.into_iter().unique().collect::<Vec<_>>();
+    let names = resource
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+
+    let api_version = match &app.api_version {
+        Some(api_version) => Some(
+            api_version
+                .parse::<GroupVersion>()
+                .map_err(|e| anyhow!("invalid --api-version {:?}: {}", api_version, e.0))?,
+        ),
+        None => None,
+    };
+
+    // only the named groups have to be discovered when every resource is group qualified
+    let groups = names
+        .iter()
+        .map(|name| match (&api_version, discovery::groups_of(name)) {
+            (_, Some(groups)) => Some(groups),
+            (Some(gv), None) if *name != discovery::ALL_RESOURCES => Some(vec![gv.group.clone()]),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|groups| groups.concat())
+        .filter(|_| !app.follow_owners);
+    let mut discovery = discovery::discover(&app.connection, &cli, &cluster_url, groups).await?;
+
+    // Common discovery, parameters, and api configuration for each requested resource
+    let mut resources = vec![];
+    // resources named on the command line, as opposed to expanded ones
+    let mut requested = vec![];
+    for name in names {
+        if name == discovery::ALL_RESOURCES {
+            let namespaced = !app.namespace.is_empty() || !app.all;
+            resources.extend(
+                discovery::watchable_resources(&discovery)
+                    .into_iter()
+                    .filter(|(_, caps)| !namespaced || caps.scope == Scope::Namespaced),
+            );
+            continue;
+        }
+        let api_version = api_version.as_ref();
+        if discovery::resolve_api_resource(&discovery, name, api_version)?.is_none()
+            && discovery.is_cached()
+        {
+            // the resource may have been installed since the cache was written
+            tracing::info!(?name, "resource not found in discovery cache, refreshing");
+            discovery = discovery.refresh().await?;
+        }
+        let (ar, caps) = discovery::resolve_api_resource(&discovery, name, api_version)?
+            .with_context(|| format!("resource {:?} not found in cluster", name))?;
+
+        let unsupported = access::unsupported_verbs(&caps);
+        if !unsupported.is_empty() {
+            bail!(
+                "resource {:?} cannot be watched, it does not support {}",
+                name,
+                unsupported.join(" and ")
+            );
+        }
+        requested.push(ar.clone());
+        resources.push((ar, caps));
+    }
+    let resources = resources
+        .into_iter()
+        .unique_by(|(ar, _)| ar.clone())
+        .collect::<Vec<_>>();
 
-    if caps.scope == Scope::Cluster && !app.namespace.is_none() {
-        panic!("{} is not a Namespaced-Resources!", resource);
+    let mut base_lp = ListParams::default();
+    // let the server close idle watches before the client side timeout hits them
+    if let Some(timeout) = app.connection.request_timeout.filter(|t| !t.is_zero()) {
+        let secs = timeout.as_secs().saturating_sub(1).clamp(1, 290);
+        base_lp = base_lp.timeout(secs as u32);
     }
 
-    let mut lp = ListParams::default();
+    let mut lp = base_lp.clone();
     if let Some(label) = app.selector.clone() {
         lp = lp.labels(label.as_str());
     }
 
-    if let Some(name) = app.name.clone() {
-        lp = lp.fields(&format!("metadata.name={}", name));
+    // field selectors can't be ORed, several names are matched client side
+    let fields = match &object_names[..] {
+        [name] => Some(format!("metadata.name={}", name)),
+        _ => None,
+    }
+    .into_iter()
+    .chain(app.field_selector.clone())
+    .join(",");
+    if !fields.is_empty() {
+        lp = lp.fields(&fields);
+    }
+
+    tracing::info!(?resource, names = ?object_names, "requested objects");
+
+    let mut watches = resources
+        .into_iter()
+        .map(|(ar, caps)| (ar, caps, lp.clone()))
+        .collect::<Vec<_>>();
+
+    // plain namespaces get a watcher each, patterns and exclusions are matched client side
+    // on a watch over all namespaces
+    let client_side = app.all || app.namespace.iter().any(|ns| namespaces::is_pattern(ns));
+    let include = if client_side { &app.namespace[..] } else { &[] };
+    let namespace_filter = NamespaceFilter::new(include, &app.exclude_namespace)?;
+    let scopes = if client_side {
+        vec![None]
+    } else if app.namespace.is_empty() {
+        vec![Some(default_namespace)]
+    } else {
+        app.namespace.iter().cloned().map(Some).unique().collect()
+    };
+
+    let mut owner_tree = None;
+    if app.follow_owners {
+        let name = match &object_names[..] {
+            [] => bail!("--follow-owners requires the name of the object to follow"),
+            [name] => name.clone(),
+            _ => bail!("--follow-owners requires exactly one object to follow"),
+        };
+        if watches.len() != 1 {
+            bail!("--follow-owners requires exactly one resource to follow");
+        }
+        let (root, root_caps, _) = &watches[0];
+        let namespace = match &scopes[..] {
+            _ if root_caps.scope == Scope::Cluster => None,
+            [Some(namespace)] => Some(namespace.clone()),
+            _ => bail!("--follow-owners requires the single namespace of the object to follow"),
+        };
+        owner_tree = Some(OwnerTree::new(root.kind.clone(), name, namespace));
+        // any namespaced kind may be owned by the root, events are left out as they
+        // refer to objects through `involvedObject` instead
+        let root = root.clone();
+        watches.extend(
+            discovery::watchable_resources(&discovery)
+                .into_iter()
+                .filter(|(ar, caps)| {
+                    caps.scope == Scope::Namespaced && *ar != root && ar.kind != "Event"
+                })
+                .map(|(ar, caps)| (ar, caps, base_lp.clone())),
+        );
+    }
+
+    let watches = watches
+        .into_iter()
+        .flat_map(|(ar, caps, lp)| {
+            let scopes = if caps.scope == Scope::Namespaced {
+                scopes.clone()
+            } else {
+                vec![None]
+            };
+            scopes
+                .into_iter()
+                .map(move |namespace| (ar.clone(), caps.clone(), lp.clone(), namespace))
+        })
+        .collect::<Vec<_>>();
+
+    // refuse what the user isn't allowed to watch before anything starts, instead of
+    // retrying forbidden requests forever
+    let reviews = watches.iter().map(|(ar, _, _, namespace)| {
+        let cli = &cli;
+        async move { access::denied_verbs(cli, ar, namespace.as_deref()).await }
+    });
+    let reviews = futures::stream::iter(reviews)
+        .buffered(REVIEW_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+    let mut forbidden = vec![];
+    let mut allowed = vec![];
+    for ((ar, caps, lp, namespace), review) in watches.into_iter().zip(reviews) {
+        let denied = match review {
+            Ok(denied) => denied,
+            // with a cached discovery this is the first request to the api-server
+            Err(error) if client::is_tls_error(&error) => {
+                return Err(client::explain_tls_error(error));
+            }
+            Err(error) => {
+                tracing::warn!(kind = ar.kind, %error, "access review failed, watching anyway");
+                vec![]
+            }
+        };
+        if denied.is_empty() {
+            allowed.push((ar, caps, lp, namespace));
+            continue;
+        }
+        let scope = match &namespace {
+            Some(namespace) => format!("in namespace {:?}", namespace),
+            None if caps.scope == Scope::Namespaced => "in all namespaces".to_owned(),
+            None => "at the cluster scope".to_owned(),
+        };
+        let resource = if ar.group.is_empty() {
+            ar.plural.clone()
+        } else {
+            format!("{}.{}", ar.plural, ar.group)
+        };
+        if requested.contains(&ar) {
+            for verb in denied {
+                forbidden.push(format!("cannot {} {} {}", verb, resource, scope));
+            }
+        } else {
+            tracing::warn!(
+                ?resource,
+                ?denied,
+                scope,
+                "skipping resource, access denied"
+            );
+        }
+    }
+    if !forbidden.is_empty() {
+        bail!("forbidden:\n  {}", forbidden.join("\n  "));
+    }
+    let watches = allowed;
+
+    // objects as exported by the last run, grouped by type
+    let resume = match (&app.export, app.resume) {
+        (Some(export), true) => {
+            let exported = persistent::load_resources(export)
+                .into_iter()
+                .into_group_map_by(|obj| obj.types.clone().unwrap_or_default());
+            Some((ResumeState::load(Path::new(export)), exported))
+        }
+        _ => None,
+    };
+
+    // what each watch gets, by type and namespace
+    let watched = watches
+        .iter()
+        .map(|(ar, _, _, namespace)| {
+            let types = TypeMeta {
+                api_version: ar.api_version.clone(),
+                kind: ar.kind.clone(),
+            };
+            (types, namespace.clone())
+        })
+        .collect::<Vec<_>>();
+
+    let (watcher_tx, mut watcher_rx) = channel(32);
+    let several = scopes.len() > 1;
+    let mut unsynced = watches.len();
+    for (ar, caps, lp, namespace) in watches {
+        let types = TypeMeta {
+            api_version: ar.api_version.clone(),
+            kind: ar.kind.clone(),
+        };
+        // a watch with other selectors lists again, other objects may match them
+        let selectors = [
+            ("labels", &lp.label_selector),
+            ("fields", &lp.field_selector),
+        ]
+        .into_iter()
+        .filter_map(|(key, selector)| selector.as_ref().map(|s| format!("{}={}", key, s)))
+        .join("&");
+        let mut id = format!(
+            "{}/{}/{}",
+            ar.api_version,
+            ar.kind,
+            namespace.as_deref().unwrap_or_default()
+        );
+        if !selectors.is_empty() {
+            id = format!("{}?{}", id, selectors);
+        }
+        let api = if app.metadata_only {
+            let namespace = namespace
+                .as_deref()
+                .filter(|_| caps.scope == Scope::Namespaced);
+            Source::Metadata(MetadataApi::new(cli.clone(), &ar.to_kube_ar(), namespace))
+        } else {
+            Source::Objects(discovery::dynamic_api(
+                ar,
+                caps,
+                cli.clone(),
+                namespace.as_deref(),
+            ))
+        };
+        let exported = resume
+            .as_ref()
+            .and_then(|(_, exported)| exported.get(&types))
+            .into_iter()
+            .flatten()
+            .filter(|obj| namespace.is_none() || obj.namespace() == namespace)
+            .cloned()
+            .collect();
+        let mut watcher = Watcher::new(api, lp, types)
+            .page_size(Some(app.chunk_size))
+            .stream_initial(true);
+        if let Some((state, _)) = &resume {
+            watcher = watcher.resume(state.clone(), id, exported);
+        }
+        if let Some(namespace) = namespace.as_deref().filter(|_| several) {
+            watcher = watcher.namespace(namespace);
+        }
+        tokio::spawn(watcher.run(watcher_tx.clone()));
     }
-    let api = discovery::dynamic_api(ar, caps, cli, &app.namespace, app.all);
 
-    tracing::info!(?resource, name = ?app.name.clone().unwrap_or_default(), "requested objects");
+    let (tx, rx): (Sender<Event>, Receiver<Event>) = channel(32);
 
-    let (tx, rx): (Sender<DynamicObject>, Receiver<DynamicObject>) = channel(32);
+    // objects owned by the followed one go by other names
+    let object_names = if owner_tree.is_some() {
+        vec![]
+    } else {
+        object_names
+    };
 
-    let export_path = app.export.clone();
+    // the exported history of what is watched now, for the tui to start from
+    let history = match &app.export {
+        Some(export) if app.mode == options::Mode::TUI => {
+            let mut tree = owner_tree.clone();
+            persistent::load_history(export)
+                .into_iter()
+                .filter(|revision| {
+                    let obj = &revision.obj;
+                    let namespace = obj.namespace();
+                    let selected = watched.iter().any(|(types, watched_namespace)| {
+                        obj.types.as_ref() == Some(types)
+                            && (watched_namespace.is_none() || *watched_namespace == namespace)
+                    }) && namespace_filter.matches(namespace.as_deref())
+                        && (object_names.is_empty() || object_names.contains(&obj.name_any()));
+                    let event = match revision.change {
+                        Change::Deleted => Event::Deleted(obj.clone()),
+                        Change::Added | Change::Modified => Event::Applied(obj.clone()),
+                    };
+                    match &mut tree {
+                        Some(tree) if selected => !tree.filter(event).is_empty(),
+                        _ => selected,
+                    }
+                })
+                .collect()
+        }
+        _ => vec![],
+    };
 
+    let resume = resume.map(|(state, _)| state);
     tokio::spawn(async move {
-        // present a dumb table for it for now. kubectl does not do this anymore.
-        let mut stream = watcher(api, lp).applied_objects().boxed();
-        loop {
-            let obj = match stream.try_next().await {
-                Ok(obj) => obj.unwrap(),
-                Err(error) => {
-                    panic!("failed to get stream response: {:?}", error)
+        if !history.is_empty() && tx.send(Event::History(history)).await.is_err() {
+            return;
+        }
+        while let Some(event) = watcher_rx.recv().await {
+            // saved only now the events before it are exported
+            if let Event::Checkpoint(id, resource_version) = &event {
+                if let Some(state) = &resume {
+                    state.set(id, resource_version);
                 }
+                continue;
+            }
+            let event = match namespace_filter
+                .filter(event)
+                .and_then(|event| retain_names(event, &object_names))
+            {
+                Some(event) => event,
+                None => continue,
             };
-            persistent::store_resource(&export_path, &obj);
-            tx.send(obj).await.unwrap();
+            let events = match owner_tree.as_mut() {
+                Some(tree) => tree.filter(event),
+                None => vec![event],
+            };
+            for mut event in events {
+                match &event {
+                    Event::Added(obj) => persistent::store_resource(&export, obj, Change::Added),
+                    Event::Applied(obj) => {
+                        persistent::store_resource(&export, obj, Change::Modified)
+                    }
+                    Event::Deleted(obj) => {
+                        persistent::store_resource(&export, obj, Change::Deleted)
+                    }
+                    Event::Restarted(objs) => {
+                        for obj in objs {
+                            persistent::store_resource(&export, obj, Change::Added);
+                        }
+                    }
+                    // a single boundary between the initial state and live changes
+                    Event::Synced(name) => {
+                        tracing::debug!(name, "initial state sent");
+                        unsynced -= 1;
+                        if unsynced > 0 {
+                            continue;
+                        }
+                        event = Event::Live;
+                    }
+                    Event::Relisted(_)
+                    | Event::Checkpoint(..)
+                    | Event::History(_)
+                    | Event::Status(..)
+                    | Event::Live
+                    | Event::Audited { .. } => {}
+                }
+                if tx.send(event).await.is_err() {
+                    return;
+                }
+            }
         }
     });
 
     return Ok(rx);
 }
+
+/// Drops the objects not named on the command line, if any are
+fn retain_names(event: Event, names: &[String]) -> Option<Event> {
+    if names.is_empty() {
+        return Some(event);
+    }
+    let named = |obj: &DynamicObject| names.contains(&obj.name_any());
+    match event {
+        Event::Added(obj) | Event::Applied(obj) | Event::Deleted(obj) if !named(&obj) => None,
+        Event::Restarted(mut objs) => {
+            objs.retain(named);
+            Some(Event::Restarted(objs))
+        }
+        event => Some(event),
+    }
+}
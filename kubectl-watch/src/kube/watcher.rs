@@ -0,0 +1,560 @@
+use crate::kube::metadata::MetadataApi;
+use crate::kube::resume::ResumeState;
+use crate::persistent::Revision;
+
+use futures::{stream::BoxStream, StreamExt};
+use kube::{
+    api::{Api, DynamicObject, ListParams, ObjectList, ResourceExt, WatchEvent},
+    core::{Request, TypeMeta},
+    error::ErrorResponse,
+    Error,
+};
+use std::{cmp::min, collections::HashMap, fmt, time::Duration};
+use tokio::sync::mpsc::{error::SendError, Sender};
+
+const BACKOFF_INITIAL: Duration = Duration::from_millis(800);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// A watch event sent to the output processes
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An object was added
+    Added(DynamicObject),
+    /// An object was modified, or added or modified where the source doesn't tell
+    Applied(DynamicObject),
+    /// An object was deleted, carrying its last known state
+    Deleted(DynamicObject),
+    /// The initial list of objects
+    Restarted(Vec<DynamicObject>),
+    /// The watch of a kind had to relist, followed by the objects changed or deleted in the meantime
+    Relisted(String),
+    /// The watch of a kind sent its whole initial state, everything after is a live change
+    Synced(String),
+    /// Every watch sent its initial state
+    Live,
+    /// The connection state to the api-server changed for the watch of a kind
+    Status(String, ConnectionState),
+    /// The resumable watch saved under the id got to the resource version, every
+    /// event of it sent before is handled once this one is
+    Checkpoint(String, String),
+    /// The revisions of the watched objects exported by earlier runs, oldest first
+    History(Vec<Revision>),
+    /// A revision read from an audit log, with who made it
+    Audited {
+        obj: DynamicObject,
+        deleted: bool,
+        actor: Actor,
+    },
+}
+
+/// Who made a change, as recorded by an audit event
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub user: String,
+    pub verb: String,
+    pub user_agent: String,
+    pub timestamp: String,
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} by {} via {} at {}",
+            self.verb, self.user, self.user_agent, self.timestamp
+        )
+    }
+}
+
+/// Connection state of a watch to the api-server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// The last request failed, the next one is sent after `delay`
+    Retrying {
+        error: String,
+        delay: Duration,
+    },
+    /// The api-server refused to list or watch the resource
+    Forbidden(String),
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connected => write!(f, "connected"),
+            Self::Retrying { error, delay } => {
+                write!(f, "retrying in {}s: {}", delay.as_secs_f32(), error)
+            }
+            Self::Forbidden(message) => write!(f, "forbidden: {}", message),
+        }
+    }
+}
+
+/// Where a watcher lists and watches objects from
+pub enum Source {
+    /// Full objects
+    Objects(Api<DynamicObject>),
+    /// Only the metadata of objects
+    Metadata(MetadataApi),
+}
+
+impl Source {
+    async fn list(&self, lp: &ListParams) -> kube::Result<ObjectList<DynamicObject>> {
+        match self {
+            Self::Objects(api) => api.list(lp).await,
+            Self::Metadata(api) => api.list(lp).await,
+        }
+    }
+
+    async fn watch(
+        &self,
+        lp: &ListParams,
+        version: &str,
+    ) -> kube::Result<BoxStream<'static, kube::Result<WatchEvent<DynamicObject>>>> {
+        match self {
+            Self::Objects(api) => Ok(api.watch(lp, version).await?.boxed()),
+            Self::Metadata(api) => api.watch(lp, version).await,
+        }
+    }
+
+    /// Watches starting with the current state as added events, the streaming
+    /// alternative to a list
+    async fn watch_initial(
+        &self,
+        lp: &ListParams,
+    ) -> kube::Result<BoxStream<'static, kube::Result<WatchEvent<DynamicObject>>>> {
+        match self {
+            Self::Objects(api) => {
+                let req = Request::new(api.resource_url())
+                    .watch(lp, "")
+                    .map_err(Error::BuildRequest)?;
+                let client = api.clone().into_client();
+                let stream = client.request_events(send_initial_events(req)).await?;
+                Ok(stream.boxed())
+            }
+            Self::Metadata(api) => api.watch_initial(lp).await,
+        }
+    }
+}
+
+/// Asks the api-server to start a watch with synthetic added events for the current
+/// state, ended by a bookmark, see KEP-3157.
+pub fn send_initial_events(mut req: http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let uri = format!(
+        "{}&sendInitialEvents=true&resourceVersionMatch=NotOlderThan",
+        req.uri()
+    );
+    *req.uri_mut() = uri.parse().expect("watch uri stays valid");
+    req
+}
+
+/// Exponential backoff between failed requests
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff {
+            next: BACKOFF_INITIAL,
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = min(self.next * 2, BACKOFF_MAX);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = BACKOFF_INITIAL;
+    }
+}
+
+/// Lists and watches a resource forever, reconnecting with backoff on errors
+/// and relisting when the resource version expires.
+pub struct Watcher {
+    api: Source,
+    lp: ListParams,
+    /// Type of the watched resource, list items don't carry it
+    types: TypeMeta,
+    /// Name of the watch in relist and status events
+    name: String,
+    /// Last known state of every listed object, keyed by uid
+    known: HashMap<String, DynamicObject>,
+    /// Whether the initial state was sent completely
+    synced: bool,
+    /// Objects per list request, or per batch of initial events
+    page_size: Option<u32>,
+    /// Get the initial state from a watch instead of a list, while the server supports it
+    stream_initial: bool,
+    /// Where the last seen resource version is saved, under which key
+    resume: Option<(ResumeState, String)>,
+    /// Resource version to start watching from instead of listing
+    resume_from: Option<String>,
+    state: Option<ConnectionState>,
+    backoff: Backoff,
+}
+
+/// Why a list didn't complete
+enum ListError {
+    Send(Box<SendError<Event>>),
+    Request(Error),
+}
+
+impl From<SendError<Event>> for ListError {
+    fn from(error: SendError<Event>) -> Self {
+        ListError::Send(Box::new(error))
+    }
+}
+
+impl From<Error> for ListError {
+    fn from(error: Error) -> Self {
+        ListError::Request(error)
+    }
+}
+
+/// Outcome of consuming a watch stream
+enum WatchEnd {
+    /// The stream was closed, continue watching from the resource version
+    Closed(String),
+    /// The resource version is too old, relist
+    Gone,
+    /// The stream failed at the resource version
+    Failed(String, Error),
+}
+
+impl Watcher {
+    pub fn new(api: Source, lp: ListParams, types: TypeMeta) -> Self {
+        Watcher {
+            api,
+            lp,
+            name: types.kind.clone(),
+            types,
+            known: HashMap::new(),
+            synced: false,
+            page_size: None,
+            stream_initial: false,
+            resume: None,
+            resume_from: None,
+            state: None,
+            backoff: Backoff::new(),
+        }
+    }
+
+    /// Tell the watch apart from the ones of the same kind in other namespaces
+    #[must_use]
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.name = format!("{} ({})", self.types.kind, namespace);
+        self
+    }
+
+    /// List in pages of `page_size` objects
+    #[must_use]
+    pub fn page_size(mut self, page_size: Option<u32>) -> Self {
+        self.page_size = page_size.filter(|size| *size > 0);
+        self
+    }
+
+    /// Try to get the initial state through a streaming watch, falling back to a
+    /// list if the server doesn't support it
+    #[must_use]
+    pub fn stream_initial(mut self, stream_initial: bool) -> Self {
+        self.stream_initial = stream_initial;
+        self
+    }
+
+    /// Save the last seen resource version under `id`, and start from the one saved
+    /// by an earlier run if any. `known` is the state of the objects back then, a
+    /// relist is compared against it if that version has expired.
+    #[must_use]
+    pub fn resume(mut self, state: ResumeState, id: String, known: Vec<DynamicObject>) -> Self {
+        self.resume_from = state.get(&id);
+        if self.resume_from.is_some() {
+            self.known = known.into_iter().map(|obj| (key(&obj), obj)).collect();
+            self.synced = true;
+        }
+        self.resume = Some((state, id));
+        self
+    }
+
+    pub async fn run(mut self, tx: Sender<Event>) -> Result<(), SendError<Event>> {
+        let mut resource_version = self.resume_from.take();
+        if resource_version.is_some() {
+            // nothing to show until the first change
+            tx.send(Event::Synced(self.name.clone())).await?;
+        }
+        loop {
+            let rv = match resource_version.take() {
+                Some(rv) => rv,
+                None => match self.list(&tx).await? {
+                    Some(rv) => rv,
+                    None => continue,
+                },
+            };
+            resource_version = match self.api.watch(&self.lp, &rv).await {
+                Ok(mut stream) => {
+                    self.set_state(&tx, ConnectionState::Connected).await?;
+                    match self.consume(&tx, &mut stream, rv).await? {
+                        WatchEnd::Closed(rv) => Some(rv),
+                        WatchEnd::Gone => None,
+                        WatchEnd::Failed(rv, error) => {
+                            self.retry(&tx, error).await?;
+                            Some(rv)
+                        }
+                    }
+                }
+                Err(Error::Api(ErrorResponse { code: 410, .. })) => None,
+                Err(error) => {
+                    self.retry(&tx, error).await?;
+                    Some(rv)
+                }
+            };
+        }
+    }
+
+    /// List all objects, returns the resource version to watch from.
+    ///
+    /// The initial state is sent page by page as it arrives, a relist is compared
+    /// against the known objects once complete.
+    async fn list(&mut self, tx: &Sender<Event>) -> Result<Option<String>, SendError<Event>> {
+        let mut fresh = vec![];
+        let listed = if self.stream_initial {
+            self.stream_list(tx, &mut fresh).await
+        } else {
+            self.paged_list(tx, &mut fresh).await
+        };
+        let rv = match listed {
+            Ok(rv) => rv,
+            Err(ListError::Send(error)) => return Err(*error),
+            Err(ListError::Request(Error::Api(ErrorResponse { code, message, .. })))
+                if self.stream_initial && (code == 400 || code == 422) =>
+            {
+                tracing::info!(
+                    kind = self.types.kind,
+                    message,
+                    "streaming initial events not supported, listing"
+                );
+                self.stream_initial = false;
+                return Ok(None);
+            }
+            Err(ListError::Request(error)) => {
+                self.retry(tx, error).await?;
+                return Ok(None);
+            }
+        };
+
+        let known = fresh
+            .iter()
+            .map(|obj| (key(obj), obj.clone()))
+            .collect::<HashMap<_, _>>();
+        let previous = std::mem::replace(&mut self.known, known);
+        if !self.synced {
+            self.synced = true;
+            // left over from an interrupted attempt
+            for (uid, obj) in previous {
+                if !self.known.contains_key(&uid) {
+                    tx.send(Event::Deleted(obj)).await?;
+                }
+            }
+            tx.send(Event::Synced(self.name.clone())).await?;
+        } else {
+            tx.send(Event::Relisted(self.name.clone())).await?;
+            for obj in fresh {
+                match previous.get(&key(&obj)) {
+                    None => tx.send(Event::Added(obj)).await?,
+                    Some(pre) if pre.resource_version() != obj.resource_version() => {
+                        tx.send(Event::Applied(obj)).await?
+                    }
+                    Some(_) => {}
+                }
+            }
+            for (uid, obj) in previous {
+                if !self.known.contains_key(&uid) {
+                    tx.send(Event::Deleted(obj)).await?;
+                }
+            }
+        }
+        if let Some(rv) = &rv {
+            self.remember(tx, rv).await?;
+        }
+        Ok(rv)
+    }
+
+    async fn paged_list(
+        &mut self,
+        tx: &Sender<Event>,
+        fresh: &mut Vec<DynamicObject>,
+    ) -> Result<Option<String>, ListError> {
+        let mut lp = self.lp.clone();
+        lp.limit = self.page_size;
+        loop {
+            let list = self.api.list(&lp).await?;
+            self.backoff.reset();
+            self.set_state(tx, ConnectionState::Connected).await?;
+            self.take_page(tx, list.items, fresh).await?;
+            match list.metadata.continue_.filter(|token| !token.is_empty()) {
+                Some(token) => lp.continue_token = Some(token),
+                None => return Ok(list.metadata.resource_version),
+            }
+        }
+    }
+
+    async fn stream_list(
+        &mut self,
+        tx: &Sender<Event>,
+        fresh: &mut Vec<DynamicObject>,
+    ) -> Result<Option<String>, ListError> {
+        let mut stream = self.api.watch_initial(&self.lp).await?;
+        self.backoff.reset();
+        self.set_state(tx, ConnectionState::Connected).await?;
+        let page_size = self.page_size.unwrap_or(u32::MAX) as usize;
+        let mut page = vec![];
+        while let Some(item) = stream.next().await {
+            match item? {
+                WatchEvent::Added(obj) | WatchEvent::Modified(obj) => {
+                    page.push(obj);
+                    if page.len() >= page_size {
+                        self.take_page(tx, std::mem::take(&mut page), fresh).await?;
+                    }
+                }
+                WatchEvent::Deleted(obj) => {
+                    page.retain(|pre| key(pre) != key(&obj));
+                    fresh.retain(|pre| key(pre) != key(&obj));
+                }
+                // the end of the initial events is marked by the first bookmark, the
+                // `k8s.io/initial-events-end` annotation isn't decoded by kube
+                WatchEvent::Bookmark(bm) => {
+                    self.take_page(tx, page, fresh).await?;
+                    return Ok(Some(bm.metadata.resource_version));
+                }
+                WatchEvent::Error(error) => return Err(Error::Api(error).into()),
+            }
+        }
+        Err(Error::Service("watch closed before the initial events ended".into()).into())
+    }
+
+    /// Takes the objects of a list as they arrive, before the initial state is
+    /// complete they are sent right away.
+    async fn take_page(
+        &mut self,
+        tx: &Sender<Event>,
+        mut page: Vec<DynamicObject>,
+        fresh: &mut Vec<DynamicObject>,
+    ) -> Result<(), SendError<Event>> {
+        for obj in page.iter_mut() {
+            obj.types = Some(self.types.clone());
+        }
+        fresh.extend(page.iter().cloned());
+        if !self.synced {
+            // an interrupted attempt may have sent some already
+            page.retain(|obj| match self.known.get(&key(obj)) {
+                Some(pre) => pre.resource_version() != obj.resource_version(),
+                None => true,
+            });
+            for obj in &page {
+                self.known.insert(key(obj), obj.clone());
+            }
+            if !page.is_empty() {
+                tx.send(Event::Restarted(page)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn consume(
+        &mut self,
+        tx: &Sender<Event>,
+        stream: &mut (impl futures::Stream<Item = kube::Result<WatchEvent<DynamicObject>>> + Unpin),
+        mut rv: String,
+    ) -> Result<WatchEnd, SendError<Event>> {
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(WatchEvent::Added(mut obj)) => {
+                    self.backoff.reset();
+                    obj.types = Some(self.types.clone());
+                    rv = obj.resource_version().unwrap_or(rv);
+                    self.known.insert(key(&obj), obj.clone());
+                    tx.send(Event::Added(obj)).await?;
+                    self.remember(tx, &rv).await?;
+                }
+                Ok(WatchEvent::Modified(mut obj)) => {
+                    self.backoff.reset();
+                    obj.types = Some(self.types.clone());
+                    rv = obj.resource_version().unwrap_or(rv);
+                    self.known.insert(key(&obj), obj.clone());
+                    tx.send(Event::Applied(obj)).await?;
+                    self.remember(tx, &rv).await?;
+                }
+                Ok(WatchEvent::Deleted(mut obj)) => {
+                    self.backoff.reset();
+                    obj.types = Some(self.types.clone());
+                    rv = obj.resource_version().unwrap_or(rv);
+                    self.known.remove(&key(&obj));
+                    tx.send(Event::Deleted(obj)).await?;
+                    self.remember(tx, &rv).await?;
+                }
+                Ok(WatchEvent::Bookmark(bm)) => {
+                    rv = bm.metadata.resource_version;
+                    self.remember(tx, &rv).await?;
+                }
+                Ok(WatchEvent::Error(error)) if error.code == 410 => return Ok(WatchEnd::Gone),
+                Ok(WatchEvent::Error(error)) => {
+                    return Ok(WatchEnd::Failed(rv, Error::Api(error)));
+                }
+                Err(error) => return Ok(WatchEnd::Failed(rv, error)),
+            }
+        }
+        Ok(WatchEnd::Closed(rv))
+    }
+
+    /// Save the resource version the watch got to, once the events sent before are
+    /// handled, e.g. exported
+    async fn remember(
+        &self,
+        tx: &Sender<Event>,
+        resource_version: &str,
+    ) -> Result<(), SendError<Event>> {
+        if let Some((_, id)) = &self.resume {
+            tx.send(Event::Checkpoint(id.clone(), resource_version.to_owned()))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Report the failure and wait before the next request
+    async fn retry(&mut self, tx: &Sender<Event>, error: Error) -> Result<(), SendError<Event>> {
+        let delay = self.backoff.next_delay();
+        tracing::warn!(%error, ?delay, "watch request failed");
+        let state = match error {
+            Error::Api(ErrorResponse {
+                code: 403, message, ..
+            }) => ConnectionState::Forbidden(message),
+            error => ConnectionState::Retrying {
+                error: error.to_string(),
+                delay,
+            },
+        };
+        self.set_state(tx, state).await?;
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+
+    async fn set_state(
+        &mut self,
+        tx: &Sender<Event>,
+        state: ConnectionState,
+    ) -> Result<(), SendError<Event>> {
+        if self.state.as_ref() != Some(&state) {
+            self.state = Some(state.clone());
+            tx.send(Event::Status(self.name.clone(), state)).await?;
+        }
+        Ok(())
+    }
+}
+
+fn key(obj: &DynamicObject) -> String {
+    obj.uid()
+        .unwrap_or_else(|| obj.namespace().unwrap_or_default() + "/" + &obj.name_any())
+}
@@ -1,23 +1,46 @@
 use anyhow::Result;
 
+mod audit;
 mod diff;
+mod input;
 mod kube;
 mod options;
 mod output;
 mod persistent;
+mod replay;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let app: options::App = clap::Parser::parse();
 
-    match kube::watch(&app).await {
+    let rx = match &app.command {
+        Some(options::Command::ApiResources(args)) => {
+            let resources = kube::api_resources(&args.connection.or(&app.connection), args).await?;
+            output::print_api_resources(&resources);
+            return Ok(());
+        }
+        Some(options::Command::Replay(args)) => replay::replay(args),
+        None if app.audit_log.is_some() => persistent::open(&app)
+            .and_then(|export| audit::read(app.audit_log.as_deref().unwrap(), export)),
+        None if app.from_stdin => {
+            persistent::open(&app).and_then(|export| input::read(input::STDIN, export))
+        }
+        None => match &app.filename {
+            Some(path) => persistent::open(&app).and_then(|export| input::read(path, export)),
+            None => kube::watch(&app).await,
+        },
+    };
+
+    match rx {
         Ok(rx) => match app.mode {
             options::Mode::TUI => output::tui_print_process(&app, rx).await?,
-            options::Mode::Simple => output::simple_print_process(rx).await?,
+            options::Mode::Simple => output::simple_print_process(&app, rx).await?,
         },
         Err(error) => {
-            panic!("{}", error)
+            // as main would print a returned error, without waiting for other tasks
+            eprintln!("Error: {:?}", error);
+            std::process::exit(1)
         }
     }
 
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(clap::ArgEnum, Clone, PartialEq, Eq)]
 pub enum Mode {
     TUI,
@@ -9,39 +11,383 @@ impl Default for Mode {
     }
 }
 
+/// How watched resources are exported
+#[derive(clap::ArgEnum, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Yaml,
+    Json,
+    Ndjson,
+    #[clap(name = "tar.gz")]
+    TarGz,
+    Git,
+}
+
+/// Compression of old exported revisions
+#[derive(clap::ArgEnum, Clone, Copy, PartialEq, Eq)]
+pub enum ExportCompression {
+    Gzip,
+    Zstd,
+}
+
 #[derive(clap::Parser)]
-#[clap(version, about, long_about = None)]
+#[clap(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
 pub struct App {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     /// Selector (label query) to filter on, supports '=', '==', and '!='. (e.g. -l key1=value1,key2=value2)
     #[clap(long, short = 'l')]
     pub selector: Option<String>,
 
-    /// If present, the namespace scope for this CLI request
-    #[clap(long, short)]
-    pub namespace: Option<String>,
+    /// Selector (field query) to filter on, supports '=', '==', and '!='. (e.g. --field-selector spec.nodeName=node-3)
+    #[clap(long)]
+    pub field_selector: Option<String>,
+
+    /// If present, the namespace scope for this CLI request, comma separated names, globs (e.g. team-*) or /regex/
+    #[clap(long, short, value_delimiter = ',')]
+    pub namespace: Vec<String>,
+
+    /// Leave out objects of these namespaces, comma separated names, globs (e.g. *-system) or /regex/
+    #[clap(long, value_delimiter = ',')]
+    pub exclude_namespace: Vec<String>,
 
     /// If present, list the requested object(s) across all namespaces
     #[clap(long, short = 'A')]
     pub all: bool,
 
     /// delta changes view mode
-    #[clap(long, arg_enum, default_value_t)]
+    #[clap(global = true, long, arg_enum, default_value_t)]
     pub mode: Mode,
 
-    /// Use tls to request api-server
-    #[clap(long)]
+    #[clap(flatten)]
+    pub connection: Connection,
+
+    /// Deprecated, the server's certificate is verified by default
+    #[clap(long, hide = true)]
     pub use_tls: bool,
 
     /// Set true to show managed fields delta changes
-    #[clap(long)]
+    #[clap(global = true, long)]
     pub include_managed_fields: bool,
 
+    /// Return large lists in chunks rather than all at once, pass 0 to disable
+    #[clap(long, default_value_t = 500)]
+    pub chunk_size: u32,
+
+    /// Hide the initial state and only show changes made after the watch started, diffed against it
+    #[clap(long, visible_alias = "since-start")]
+    pub only_changes: bool,
+
+    /// Only fetch and diff metadata: labels, annotations, ownerReferences, finalizers and generation
+    #[clap(long)]
+    pub metadata_only: bool,
+
+    /// Watch the named object and every object it owns, directly or transitively
+    #[clap(long)]
+    pub follow_owners: bool,
+
+    /// Pin unqualified resource names to this api version (e.g. apps/v1, v1)
+    #[clap(long)]
+    pub api_version: Option<String>,
+
+    /// Read a watch stream instead of watching, e.g. from `kubectl get -w -o json`, '-' for stdin
+    #[clap(long, short = 'f', conflicts_with = "from-stdin")]
+    pub filename: Option<String>,
+
+    /// Read a watch stream from stdin, the same as `-f -`
+    #[clap(long)]
+    pub from_stdin: bool,
+
+    /// Read the changes from an api-server audit log with RequestResponse level bodies instead of watching, '-' for stdin
+    #[clap(long, conflicts_with_all = &["filename", "from-stdin"])]
+    pub audit_log: Option<String>,
+
     /// A path, where all watched resources will be stored
     #[clap(long)]
     pub export: Option<String>,
 
-    /// Support resource 'plural', 'kind' and 'shortname'
-    pub resource: String,
-    /// Resource name, optional
-    pub name: Option<String>,
+    /// Export a yaml or json file per revision, one rotating ndjson file of watch events, a tar.gz bundle per session, or a git repository with a commit per revision
+    #[clap(long, arg_enum, default_value_t, requires = "export")]
+    pub export_format: ExportFormat,
+
+    /// Keep at most this many exported revisions of every object, with the yaml and json export formats
+    #[clap(long, requires = "export")]
+    pub export_max_revisions: Option<usize>,
+
+    /// Remove exported revisions older than this (e.g. 12h, 14d), the latest revision of every object is kept with the yaml and json export formats, rotated ndjson files and old bundles are removed whole
+    #[clap(long, value_parser = parse_duration, requires = "export")]
+    pub export_max_age: Option<Duration>,
+
+    /// Remove the oldest exported revisions once the export grows beyond this size (e.g. 500Mi, 20Gi), the latest revision of every object is kept with the yaml and json export formats, rotated ndjson files and old bundles are removed whole
+    #[clap(long, value_parser = parse_size, requires = "export")]
+    pub export_max_bytes: Option<u64>,
+
+    /// Compress exported revisions once a newer one of the same object arrived, and rotated ndjson files
+    #[clap(long, arg_enum, requires = "export")]
+    pub export_compress: Option<ExportCompression>,
+
+    /// Resume the watches from where the last run with the same --export stopped, instead of listing everything again
+    #[clap(long, requires = "export")]
+    pub resume: bool,
+
+    /// Support resource 'plural', 'kind' and 'shortname', optionally qualified as 'plural.group' or 'plural.version.group', comma separated (e.g. deploy,rs,pods), or 'all' for every watchable resource
+    #[clap(required_unless_present_any = &["filename", "from-stdin", "audit-log"])]
+    pub resource: Option<String>,
+    /// Resource names, optional, the first one can also be given as 'resource/name'
+    #[clap(value_name = "NAME")]
+    pub names: Vec<String>,
+}
+
+/// How to reach the cluster, for the watch and api-resources but not replay
+#[derive(clap::Args, Clone)]
+pub struct Connection {
+    /// Path to the kubeconfig file to use for CLI requests
+    #[clap(long)]
+    pub kubeconfig: Option<String>,
+
+    /// The name of the kubeconfig context to use
+    #[clap(long)]
+    pub context: Option<String>,
+
+    /// The name of the kubeconfig cluster to use
+    #[clap(long)]
+    pub cluster: Option<String>,
+
+    /// The name of the kubeconfig user to use
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Username to impersonate for the operation
+    #[clap(long = "as")]
+    pub impersonate: Option<String>,
+
+    /// Group to impersonate for the operation, this flag can be repeated to specify multiple groups
+    #[clap(long = "as-group")]
+    pub impersonate_groups: Vec<String>,
+
+    /// The length of time to wait before giving up on a single server request (e.g. 1s, 2m, 3h), zero means no timeout
+    #[clap(long, value_parser = parse_duration)]
+    pub request_timeout: Option<Duration>,
+
+    /// Default cache directory [default: ~/.kube/cache]
+    #[clap(long)]
+    pub cache_dir: Option<String>,
+
+    /// How long cached api discovery stays valid (e.g. 10m, 6h) [default: 6h]
+    #[clap(long, value_parser = parse_duration)]
+    pub discovery_cache_ttl: Option<Duration>,
+
+    /// Ignore the api discovery cache and query the api-server again
+    #[clap(long)]
+    pub refresh_discovery: bool,
+
+    /// Only discover these api groups, comma separated, 'core' is the legacy core group
+    #[clap(
+        long,
+        env = "KUBECTL_WATCH_DISCOVERY_ALLOW_GROUPS",
+        value_delimiter = ','
+    )]
+    pub discovery_allow_groups: Vec<String>,
+
+    /// Never discover these api groups, comma separated (e.g. metrics.k8s.io)
+    #[clap(
+        long,
+        env = "KUBECTL_WATCH_DISCOVERY_BLOCK_GROUPS",
+        value_delimiter = ','
+    )]
+    pub discovery_block_groups: Vec<String>,
+
+    /// If true, the server's certificate will not be checked for validity. This will make your HTTPS connections insecure
+    #[clap(long)]
+    pub insecure_skip_tls_verify: bool,
+
+    /// Path to a cert file for the certificate authority
+    #[clap(long, conflicts_with = "insecure-skip-tls-verify")]
+    pub certificate_authority: Option<String>,
+}
+
+impl Connection {
+    /// Every flag not given here taken from `fallback`, e.g. the flags given before
+    /// the api-resources subcommand
+    pub fn or(&self, fallback: &Connection) -> Connection {
+        let vec_or = |v: &Vec<String>, fallback: &Vec<String>| {
+            if v.is_empty() {
+                fallback.clone()
+            } else {
+                v.clone()
+            }
+        };
+        Connection {
+            kubeconfig: self.kubeconfig.clone().or(fallback.kubeconfig.clone()),
+            context: self.context.clone().or(fallback.context.clone()),
+            cluster: self.cluster.clone().or(fallback.cluster.clone()),
+            user: self.user.clone().or(fallback.user.clone()),
+            impersonate: self.impersonate.clone().or(fallback.impersonate.clone()),
+            impersonate_groups: vec_or(&self.impersonate_groups, &fallback.impersonate_groups),
+            request_timeout: self.request_timeout.or(fallback.request_timeout),
+            cache_dir: self.cache_dir.clone().or(fallback.cache_dir.clone()),
+            discovery_cache_ttl: self.discovery_cache_ttl.or(fallback.discovery_cache_ttl),
+            refresh_discovery: self.refresh_discovery || fallback.refresh_discovery,
+            discovery_allow_groups: vec_or(
+                &self.discovery_allow_groups,
+                &fallback.discovery_allow_groups,
+            ),
+            discovery_block_groups: vec_or(
+                &self.discovery_block_groups,
+                &fallback.discovery_block_groups,
+            ),
+            insecure_skip_tls_verify: self.insecure_skip_tls_verify
+                || fallback.insecure_skip_tls_verify,
+            certificate_authority: self
+                .certificate_authority
+                .clone()
+                .or(fallback.certificate_authority.clone()),
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Print the api resources found by discovery
+    ApiResources(Box<ApiResources>),
+    /// Play an export directory back without a cluster
+    Replay(Replay),
+}
+
+#[derive(clap::Args)]
+pub struct Replay {
+    /// Export directory written with --export
+    pub dir: String,
+
+    /// Pace the playback by the recorded times, sped up by this factor (e.g. 1 for real time, 60 for a minute per second), all at once if absent
+    #[clap(long)]
+    pub speed: Option<f64>,
+}
+
+#[derive(clap::Args)]
+pub struct ApiResources {
+    #[clap(flatten)]
+    pub connection: Connection,
+
+    /// Only show resources which support list and watch
+    #[clap(long)]
+    pub watchable: bool,
+
+    /// If false, only non-namespaced resources will be returned, otherwise only namespaced ones
+    #[clap(
+        long,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub namespaced: Option<bool>,
+}
+
+/// Parses a kubectl style duration, a number with an optional unit of s, m, h or d
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", value))?;
+    let unit: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "invalid duration unit {:?}, use s, m, h or d",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {:?} is too long", value))
+}
+
+/// Parses a size in bytes with an optional unit of K, M, G, Ki, Mi or Gi
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, ""),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", value))?;
+    let unit: u64 = match unit {
+        "" => 1,
+        "K" => 1000,
+        "M" => 1000 * 1000,
+        "G" => 1000 * 1000 * 1000,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "invalid size unit {:?}, use K, M, G, Ki, Mi or Gi",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("14d"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn test_parse_duration_invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("2w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2K"), Ok(2000));
+        assert_eq!(parse_size("3M"), Ok(3_000_000));
+        assert_eq!(parse_size("1G"), Ok(1_000_000_000));
+        assert_eq!(parse_size("2Ki"), Ok(2048));
+        assert_eq!(parse_size("500Mi"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("20Gi"), Ok(20 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn test_parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("Gi").is_err());
+        assert!(parse_size("5GB").is_err());
+        assert!(parse_size("5k").is_err());
+        assert!(parse_size("99999999999Gi").is_err());
+    }
 }
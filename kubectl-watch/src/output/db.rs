@@ -10,6 +10,7 @@ pub trait UID {
 
 pub trait Database<T: UID> {
     fn do_insert(&mut self, obj: T);
+    fn contains(&self, obj: &T) -> bool;
     fn items_of(&self, obj: &T) -> Option<&Vec<T>>;
     fn items_of_uid(&self, uid: String) -> Option<&Vec<T>>;
     fn index_of(&self, obj: &T) -> usize;
@@ -25,6 +26,13 @@ impl<T: UID> Database<T> for Memory<T> {
         }
     }
 
+    fn contains(&self, obj: &T) -> bool {
+        self.get(&obj.uid()).is_some_and(|list| {
+            list.iter()
+                .any(|item| item.resource_version() == obj.resource_version())
+        })
+    }
+
     fn items_of(&self, obj: &T) -> Option<&Vec<T>> {
         self.get(&obj.uid())
     }
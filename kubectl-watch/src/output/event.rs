@@ -1,11 +1,12 @@
+use crate::kube::Event;
+
 use crossterm::event::KeyEvent;
-use kube::api::DynamicObject;
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub enum Msg {
     Key(KeyEvent),
-    Obj(DynamicObject),
+    Watch(Event),
 }
 
 pub fn new_chan() -> (mpsc::Sender<Msg>, mpsc::Receiver<Msg>) {
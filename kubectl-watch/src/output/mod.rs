@@ -3,29 +3,86 @@ mod event;
 mod tui;
 mod utils;
 
+use crate::kube::{Actor, ApiCapabilities, ApiResource, Event};
 use crate::options;
 use kube::api::{DynamicObject, ResourceExt};
+use kube::discovery::Scope;
 use tokio::sync::mpsc::Receiver;
 
-pub async fn simple_print_process(mut rx: Receiver<DynamicObject>) -> std::io::Result<()> {
-    println!("{0:<width$} {1:<20}", "NAME", "AGE", width = 63);
-    while let Some(obj) = rx.recv().await {
-        let age = utils::format_creation_since(obj.creation_timestamp());
-        println!("{0:<width$} {1:<20}", obj.name_any(), age, width = 63);
+/// Marks output of a `--metadata-only` watch
+const METADATA_ONLY_BADGE: &str = "[metadata only, bodies not fetched]";
+
+fn simple_print_line(obj: &DynamicObject, deleted: bool, actor: Option<&Actor>) {
+    let age = if deleted {
+        "DELETED".to_owned()
+    } else {
+        utils::format_creation_since(obj.creation_timestamp())
+    };
+    let kind = obj
+        .types
+        .as_ref()
+        .map(|t| t.kind.clone())
+        .unwrap_or_default();
+    let who = actor
+        .map(|actor| format!("{} ({})", actor.user, actor.verb))
+        .unwrap_or_default();
+    println!(
+        "{0:<20} {1:<width$} {2:<20} {3}",
+        kind,
+        obj.name_any(),
+        age,
+        who,
+        width = 63
+    );
+}
+
+pub async fn simple_print_process(
+    app: &options::App,
+    mut rx: Receiver<Event>,
+) -> std::io::Result<()> {
+    if app.metadata_only {
+        println!("{}", METADATA_ONLY_BADGE);
+    }
+    println!(
+        "{0:<20} {1:<width$} {2:<20} {3}",
+        "KIND",
+        "NAME",
+        "AGE",
+        if app.audit_log.is_some() { "WHO" } else { "" },
+        width = 63
+    );
+    while let Some(event) = rx.recv().await {
+        match event {
+            Event::Added(obj) | Event::Applied(obj) => simple_print_line(&obj, false, None),
+            Event::Deleted(obj) => simple_print_line(&obj, true, None),
+            Event::Restarted(_) if app.only_changes => {}
+            Event::Restarted(objs) => {
+                for obj in objs {
+                    simple_print_line(&obj, false, None);
+                }
+            }
+            Event::Relisted(kind) => println!("--- {} relisted ---", kind),
+            Event::Live if app.only_changes => {}
+            Event::Live => println!("--- initial state above, live changes below ---"),
+            Event::Synced(_) | Event::Checkpoint(..) | Event::History(_) => {}
+            Event::Status(kind, state) => println!("--- {}: {} ---", kind, state),
+            Event::Audited {
+                obj,
+                deleted,
+                actor,
+            } => simple_print_line(&obj, deleted, Some(&actor)),
+        }
     }
     Ok(())
 }
 
-pub async fn tui_print_process(
-    app: &options::App,
-    mut rx: Receiver<DynamicObject>,
-) -> anyhow::Result<()> {
+pub async fn tui_print_process(app: &options::App, mut rx: Receiver<Event>) -> anyhow::Result<()> {
     let (sender, receiver) = event::new_chan();
     let sender2 = sender.clone();
 
     tokio::spawn(async move {
-        while let Some(obj) = rx.recv().await {
-            sender.send(event::Msg::Obj(obj)).await.unwrap();
+        while let Some(event) = rx.recv().await {
+            sender.send(event::Msg::Watch(event)).await.unwrap();
         }
     });
 
@@ -40,3 +97,47 @@ pub async fn tui_print_process(
     // draw terminal ui
     tui::main_tui(app, receiver).await
 }
+
+/// Prints discovered resources in the columns of `kubectl api-resources -o wide`
+pub fn print_api_resources(resources: &[(ApiResource, ApiCapabilities)]) {
+    let header = [
+        "NAME",
+        "SHORTNAMES",
+        "APIVERSION",
+        "NAMESPACED",
+        "KIND",
+        "VERBS",
+    ];
+    let rows = resources
+        .iter()
+        .map(|(ar, caps)| {
+            [
+                ar.plural.clone(),
+                ar.short_names.clone().unwrap_or_default().join(","),
+                ar.api_version.clone(),
+                (caps.scope == Scope::Namespaced).to_string(),
+                ar.kind.clone(),
+                caps.operations.join(","),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let print_row = |cells: &[&str]| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("   ");
+        println!("{}", line.trim_end());
+    };
+    print_row(&header);
+    for row in &rows {
+        print_row(&row.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+}
@@ -1,9 +1,11 @@
 use crate::diff;
+use crate::kube::{Actor, ConnectionState, Event};
 use crate::options;
 use crate::output::{
     db::{Database, Memory, UID},
-    event, utils,
+    event, utils, METADATA_ONLY_BADGE,
 };
+use crate::persistent::{Change, Revision};
 
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture, KeyCode},
@@ -20,35 +22,99 @@ use ratatui::{
     Frame,
     Terminal,
 };
-use std::{collections::HashMap, io};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
 use tokio::sync::mpsc;
 
-impl UID for DynamicObject {
+/// A revision of a watched object, or the tombstone left by its deletion
+#[derive(Clone)]
+struct Record {
+    obj: DynamicObject,
+    deleted: bool,
+    /// Who made the revision, when it was read from an audit log
+    actor: Option<Actor>,
+}
+
+impl Record {
+    fn applied(obj: DynamicObject) -> Self {
+        Record {
+            obj,
+            deleted: false,
+            actor: None,
+        }
+    }
+
+    fn tombstone(obj: DynamicObject) -> Self {
+        Record {
+            obj,
+            deleted: true,
+            actor: None,
+        }
+    }
+
+    fn kind(&self) -> String {
+        self.obj
+            .types
+            .as_ref()
+            .map(|t| t.kind.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl UID for Record {
     fn resource_version(&self) -> String {
-        ResourceExt::resource_version(self).unwrap()
+        let rv = ResourceExt::resource_version(&self.obj).unwrap();
+        if self.deleted {
+            // keep tombstones apart from the revision they were made of
+            rv + "-deleted"
+        } else {
+            rv
+        }
     }
     fn uid(&self) -> String {
-        let name = self.name_any();
-        let namespace = self.namespace().unwrap_or_default();
-        name + &namespace
+        let name = self.obj.name_any();
+        let namespace = self.obj.namespace().unwrap_or_default();
+        name + &namespace + &self.kind()
     }
 }
 
+/// A row of the resources table
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum Item {
+    Record(Record),
+    /// A marker between records, e.g. where the watch relisted
+    Separator(String),
+}
+
 struct Controller<'a> {
     diff_tool: Box<dyn diff::Diff<'a>>,
     state: TableState,
-    items: Vec<DynamicObject>,
-    total_items: Vec<DynamicObject>,
+    items: Vec<Item>,
+    total_items: Vec<Item>,
     active_uid: Option<String>,
-    database: Memory<DynamicObject>,
+    database: Memory<Record>,
     l_diff: Paragraph<'a>,
     r_diff: Paragraph<'a>,
     scroll: u16,
     scroll_step: u16,
+    connection: BTreeMap<String, ConnectionState>,
+    /// Nest objects under their owner instead of listing them as they arrive
+    nested: bool,
+    /// Depth of each object in the ownership tree, keyed by `metadata.uid`
+    depths: HashMap<String, usize>,
+    /// Only the metadata of objects was fetched
+    metadata_only: bool,
+    /// Keep the initial state as baselines for diffs, without rows of its own
+    only_changes: bool,
+    /// Records come from an audit log and know who made them
+    audited: bool,
 }
 
 impl<'a> Controller<'a> {
-    fn new(diff_tool: Box<dyn diff::Diff<'a>>) -> Controller<'a> {
+    fn new(diff_tool: Box<dyn diff::Diff<'a>>, app: &options::App) -> Controller<'a> {
         Controller {
             diff_tool: diff_tool,
             state: TableState::default(),
@@ -60,44 +126,212 @@ impl<'a> Controller<'a> {
             r_diff: Paragraph::new(""),
             scroll: 0,
             scroll_step: 5,
+            connection: BTreeMap::new(),
+            nested: app.follow_owners,
+            depths: HashMap::new(),
+            metadata_only: app.metadata_only,
+            only_changes: app.only_changes,
+            audited: app.audit_log.is_some(),
         }
     }
 
     fn get_raws(&mut self) -> Vec<Vec<String>> {
         let mut raws = vec![];
         for (pos, item) in self.items.iter().enumerate() {
-            raws.push(vec![
+            let item = match item {
+                Item::Record(record) => record,
+                Item::Separator(label) => {
+                    let mut raw = vec![
+                        (pos + 1).to_string(),
+                        "".to_owned(),
+                        "".to_owned(),
+                        format!("--- {} ---", label),
+                        "".to_owned(),
+                        "".to_owned(),
+                    ];
+                    if self.audited {
+                        raw.push("".to_owned());
+                    }
+                    raws.push(raw);
+                    continue;
+                }
+            };
+            let age = if item.deleted {
+                "DELETED".to_owned()
+            } else {
+                utils::format_creation_since(item.obj.creation_timestamp())
+            };
+            let depth = self.depths.get(&item.obj.uid().unwrap_or_default());
+            let name = match depth {
+                Some(depth) if *depth > 0 => {
+                    format!("{}└ {}", "  ".repeat(depth - 1), item.obj.name_any())
+                }
+                _ => item.obj.name_any(),
+            };
+            let mut raw = vec![
                 (pos + 1).to_string(),
-                item.namespace().unwrap_or("".to_owned()),
-                item.name_any().to_owned(),
-                utils::format_creation_since(item.creation_timestamp()),
-                ResourceExt::resource_version(item).unwrap_or("".to_owned()),
-            ])
+                item.kind(),
+                item.obj.namespace().unwrap_or("".to_owned()),
+                name,
+                age,
+                ResourceExt::resource_version(&item.obj).unwrap_or("".to_owned()),
+            ];
+            if self.audited {
+                raw.push(
+                    item.actor
+                        .as_ref()
+                        .map(|actor| format!("{} ({})", actor.user, actor.verb))
+                        .unwrap_or_default(),
+                );
+            }
+            raws.push(raw)
         }
         return raws;
     }
 
     fn get_header<'b>(&mut self) -> Vec<&'b str> {
-        return vec!["ID", "NAMESPACE", "NAME", "AGE", "REV"];
+        let mut header = vec!["ID", "KIND", "NAMESPACE", "NAME", "AGE", "REV"];
+        if self.audited {
+            header.push("WHO");
+        }
+        return header;
+    }
+
+    fn get_widths(&self) -> Vec<Constraint> {
+        let percentages: &[u16] = if self.audited {
+            &[5, 12, 15, 25, 10, 10, 23]
+        } else {
+            &[5, 15, 20, 30, 15, 15]
+        };
+        percentages
+            .iter()
+            .map(|p| Constraint::Percentage(*p))
+            .collect()
+    }
+
+    fn get_title(&self) -> String {
+        if self.connection.is_empty() {
+            return "Resources".to_owned();
+        }
+        let troubles = self
+            .connection
+            .iter()
+            .filter(|(_, state)| **state != ConnectionState::Connected)
+            .map(|(kind, state)| format!("{}: {}", kind, state))
+            .collect::<Vec<_>>();
+        if troubles.is_empty() {
+            format!("Resources ({})", ConnectionState::Connected)
+        } else {
+            format!("Resources ({})", troubles.join(", "))
+        }
+    }
+
+    fn get_diff_title(&self) -> String {
+        let mut title = "Diff Result".to_owned();
+        if self.metadata_only {
+            title = format!("{} {}", title, METADATA_ONLY_BADGE);
+        }
+        let selected = self.state.selected().and_then(|i| self.items.get(i));
+        if let Some(Item::Record(Record {
+            actor: Some(actor), ..
+        })) = selected
+        {
+            title = format!("{} ({})", title, actor);
+        }
+        title
     }
 
     fn _reset_scroll(&mut self) {
         self.scroll = 0
     }
 
-    fn _do_insert(&mut self, obj: DynamicObject) {
-        self.database.do_insert(obj.clone());
-        self.total_items.push(obj);
+    fn _do_insert(&mut self, record: Record) {
+        // e.g. the listed state of an object already loaded from the export
+        if self.database.contains(&record) {
+            return;
+        }
+        self.database.do_insert(record.clone());
+        self.total_items.push(Item::Record(record));
+    }
+
+    /// Loads the revisions recorded by earlier runs
+    fn seed(&mut self, revisions: Vec<Revision>) {
+        if revisions.is_empty() {
+            return;
+        }
+        for revision in revisions {
+            let record = match revision.change {
+                Change::Deleted => Record::tombstone(revision.obj),
+                Change::Added | Change::Modified => Record::applied(revision.obj),
+            };
+            if self.only_changes {
+                self.database.do_insert(record);
+            } else {
+                self._do_insert(record);
+            }
+        }
+        if !self.only_changes {
+            self.total_items
+                .push(Item::Separator("exported history above".to_owned()));
+        }
+        self._refresh_items();
+    }
+
+    fn _on_event(&mut self, event: Event) {
+        match event {
+            Event::Added(obj) | Event::Applied(obj) => self._do_insert(Record::applied(obj)),
+            Event::Deleted(obj) => self._do_insert(Record::tombstone(obj)),
+            Event::Restarted(objs) if self.only_changes => {
+                for obj in objs {
+                    let record = Record::applied(obj);
+                    if !self.database.contains(&record) {
+                        self.database.do_insert(record);
+                    }
+                }
+            }
+            Event::Restarted(objs) => {
+                for obj in objs {
+                    self._do_insert(Record::applied(obj));
+                }
+            }
+            Event::Relisted(kind) => self
+                .total_items
+                .push(Item::Separator(format!("{} relisted", kind))),
+            Event::Live if self.only_changes => {}
+            Event::Live => self.total_items.push(Item::Separator(
+                "initial state above, live changes below".to_owned(),
+            )),
+            Event::Synced(_) | Event::Checkpoint(..) => {}
+            Event::History(revisions) => self.seed(revisions),
+            Event::Status(kind, state) => {
+                self.connection.insert(kind, state);
+            }
+            Event::Audited {
+                obj,
+                deleted,
+                actor,
+            } => self._do_insert(Record {
+                obj,
+                deleted,
+                actor: Some(actor),
+            }),
+        }
         self._refresh_items();
     }
 
     fn _refresh_items(&mut self) {
         match &self.active_uid {
             Some(uid) => {
-                self.items = vec![];
-                self.items
-                    .extend_from_slice(self.database.items_of_uid(uid.clone()).unwrap());
+                self.items = self
+                    .database
+                    .items_of_uid(uid.clone())
+                    .unwrap()
+                    .iter()
+                    .cloned()
+                    .map(Item::Record)
+                    .collect();
             }
+            None if self.nested => self.items = self._nested_items(),
             None => {
                 self.items = vec![];
                 self.items.extend_from_slice(&self.total_items);
@@ -105,9 +339,73 @@ impl<'a> Controller<'a> {
         }
     }
 
+    /// Orders all records depth-first along the ownership tree, every object
+    /// keeping its own revisions in order of arrival.
+    fn _nested_items(&mut self) -> Vec<Item> {
+        let mut first_seen: HashMap<String, usize> = HashMap::new();
+        let mut owner_of: HashMap<String, String> = HashMap::new();
+        for (pos, item) in self.total_items.iter().enumerate() {
+            if let Item::Record(record) = item {
+                let uid = record.obj.uid().unwrap_or_default();
+                first_seen.entry(uid.clone()).or_insert(pos);
+                let owners = record.obj.owner_references();
+                let owner = owners
+                    .iter()
+                    .find(|owner| owner.controller == Some(true))
+                    .or_else(|| owners.first());
+                if let Some(owner) = owner {
+                    owner_of.insert(uid, owner.uid.clone());
+                }
+            }
+        }
+
+        // path of first appearances from the root down to the object
+        let path_of = |uid: &String| {
+            let mut path = vec![];
+            let mut current = Some(uid);
+            while let Some(uid) = current {
+                match first_seen.get(uid) {
+                    Some(pos) if !path.contains(pos) => path.insert(0, *pos),
+                    _ => break,
+                }
+                current = owner_of.get(uid);
+            }
+            path
+        };
+
+        let mut records = self
+            .total_items
+            .iter()
+            .enumerate()
+            .filter_map(|(pos, item)| match item {
+                Item::Record(record) => {
+                    let path = path_of(&record.obj.uid().unwrap_or_default());
+                    Some((path, pos, item.clone()))
+                }
+                Item::Separator(_) => None,
+            })
+            .collect::<Vec<_>>();
+        records.sort_by(|(l_path, l_pos, _), (r_path, r_pos, _)| {
+            l_path.cmp(r_path).then(l_pos.cmp(r_pos))
+        });
+
+        self.depths = first_seen
+            .keys()
+            .map(|uid| (uid.clone(), path_of(uid).len().saturating_sub(1)))
+            .collect();
+        records.into_iter().map(|(_, _, item)| item).collect()
+    }
+
     fn _do_diff(&mut self, select: usize) {
-        if let Some(obj) = self.items.get(select) {
-            (self.l_diff, self.r_diff) = self.diff_tool.tui_diff(self.database.sibling(obj), obj);
+        if let Some(Item::Record(record)) = self.items.get(select) {
+            (self.l_diff, self.r_diff) = if record.deleted {
+                self.diff_tool.tui_diff(Some(&record.obj), None)
+            } else {
+                let pre = self.database.sibling(record).map(|r| &r.obj);
+                self.diff_tool.tui_diff(pre, Some(&record.obj))
+            };
+        } else {
+            (self.l_diff, self.r_diff) = (Paragraph::new(""), Paragraph::new(""));
         }
     }
 
@@ -146,7 +444,7 @@ impl<'a> Controller<'a> {
     pub fn enter(&mut self) {
         match self.state.selected() {
             Some(i) => {
-                if let Some(obj) = self.items.clone().get(i) {
+                if let Some(Item::Record(obj)) = self.items.clone().get(i) {
                     if self.active_uid == Some(UID::uid(obj)) {
                         return;
                     }
@@ -169,14 +467,14 @@ impl<'a> Controller<'a> {
 
                 match self.state.selected() {
                     Some(i) => {
-                        if let Some(obj) = self.items.clone().get(i) {
+                        if let Some(Item::Record(obj)) = self.items.clone().get(i) {
                             self._refresh_items();
                             let mut select: usize = 0;
                             for (i, item) in self.items.iter().enumerate() {
-                                if ResourceExt::resource_version(item)
-                                    == ResourceExt::resource_version(obj)
-                                {
-                                    select = i;
+                                if let Item::Record(item) = item {
+                                    if UID::resource_version(item) == UID::resource_version(obj) {
+                                        select = i;
+                                    }
                                 }
                             }
                             self._do_diff(select);
@@ -216,7 +514,7 @@ pub async fn main_tui(app: &options::App, chan: mpsc::Receiver<event::Msg>) -> a
 
     // create ctrl and run it
     let diff_tool = diff::new(app);
-    let ctrl = Controller::new(diff_tool);
+    let ctrl = Controller::new(diff_tool, app);
     let res = run_tui(&mut terminal, ctrl, chan).await;
 
     // restore terminal
@@ -256,7 +554,7 @@ async fn run_tui<B: Backend>(
                     KeyCode::PageDown => ctrl.page_down(),
                     _ => {}
                 },
-                event::Msg::Obj(obj) => ctrl._do_insert(obj),
+                event::Msg::Watch(event) => ctrl._on_event(event),
             }
         }
     }
@@ -279,38 +577,42 @@ fn draw_resources_event(f: &mut Frame, ctrl: &mut Controller, area: Rect) {
         .bottom_margin(0);
 
     let r = ctrl.get_raws();
-    let rows = r.iter().map(|item| {
+    let rows = r.iter().zip(ctrl.items.iter()).map(|(item, record)| {
         let height = &item
             .iter()
             .map(|content| content.chars().filter(|c| *c == '\n').count())
             .max()
             .unwrap_or(0)
             + 1;
+        let color = match record {
+            Item::Record(record) if record.deleted => Color::LightRed,
+            Item::Record(_) => Color::White,
+            Item::Separator(_) => Color::LightBlue,
+        };
         let cells = item
             .iter()
-            .map(|c| Cell::from(c.to_string()).style(Style::default().fg(Color::White)));
+            .map(|c| Cell::from(c.to_string()).style(Style::default().fg(color)));
         Row::new(cells).height(height as u16).bottom_margin(0)
     });
 
+    let widths = ctrl.get_widths();
     let t = Table::new(rows)
         .header(header)
-        .block(Block::default().borders(Borders::ALL).title("Resources"))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(ctrl.get_title()),
+        )
         .highlight_style(selected_style)
-        .widths(&[
-            // Constraint::Percentage(10),
-            Constraint::Percentage(5),
-            Constraint::Percentage(30),
-            Constraint::Percentage(30),
-            Constraint::Percentage(20),
-            Constraint::Percentage(15),
-            // Constraint::Min(10),
-        ]);
+        .widths(&widths);
     f.render_stateful_widget(t, area, &mut ctrl.state);
 }
 
 fn draw_diff(f: &mut Frame, ctrl: &mut Controller, area: Rect) {
     f.render_widget(
-        Block::default().borders(Borders::ALL).title("Diff Result"),
+        Block::default()
+            .borders(Borders::ALL)
+            .title(ctrl.get_diff_title()),
         area,
     );
 
@@ -4,7 +4,10 @@ use k8s_openapi::{
 };
 
 pub fn format_creation_since(time: Option<Time>) -> String {
-    format_duration(Utc::now().signed_duration_since(time.unwrap().0))
+    match time {
+        Some(time) => format_duration(Utc::now().signed_duration_since(time.0)),
+        None => "<unknown>".to_owned(),
+    }
 }
 
 fn format_duration(dur: Duration) -> String {
@@ -0,0 +1,393 @@
+use super::{Change, Revision, Sidecar};
+
+use anyhow::{Context, Result};
+use git2::{Repository, Signature, Sort};
+use kube::api::{DynamicObject, ResourceExt};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Author of commits whose manager is unknown
+const AUTHOR: &str = "kubectl-watch";
+const AUTHOR_EMAIL: &str = "kubectl-watch@localhost";
+
+/// A git repository with a file per object, every received revision is a commit
+pub struct GitExport {
+    repo: Repository,
+}
+
+impl GitExport {
+    /// Opens the repository of the export directory, creating it if there is none
+    pub fn open(dir: &Path) -> Result<Self> {
+        let repo = match Repository::open(dir) {
+            Ok(repo) => repo,
+            Err(_) => Repository::init(dir)
+                .with_context(|| format!("failed to create a git repository in {:?}", dir))?,
+        };
+        if repo.is_bare() {
+            anyhow::bail!("{:?} is a bare git repository, it needs a work tree", dir);
+        }
+        Ok(GitExport { repo })
+    }
+
+    /// Commits a revision of an object to its file, or the removal of the file on
+    /// deletion. A revision which leaves the file as it is, e.g. a relist of an
+    /// unchanged object, makes no commit.
+    pub fn commit(&self, path: &Path, obj: &DynamicObject, sidecar: &Sidecar) -> Result<()> {
+        let workdir = self.repo.workdir().unwrap();
+        let file = workdir.join(path);
+        let mut index = self.repo.index()?;
+        if sidecar.event == Change::Deleted {
+            if !file.exists() {
+                return Ok(());
+            }
+            fs::remove_file(&file)?;
+            index.remove_path(path)?;
+        } else {
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&file, serde_yaml::to_string(obj)?)?;
+            index.add_path(path)?;
+        }
+        self.commit_index(&mut index, &message(obj, sidecar), manager(obj))
+    }
+
+    /// Commits a file of the export directory other than an object, e.g. the marker
+    pub fn commit_file(&self, path: &Path, message: &str) -> Result<()> {
+        let mut index = self.repo.index()?;
+        index.add_path(path)?;
+        self.commit_index(&mut index, message, None)
+    }
+
+    fn commit_index(
+        &self,
+        index: &mut git2::Index,
+        message: &str,
+        author: Option<String>,
+    ) -> Result<()> {
+        let tree_id = index.write_tree()?;
+        index.write()?;
+        let parent = match self.repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(_) => None,
+        };
+        if parent.as_ref().is_some_and(|p| p.tree_id() == tree_id) {
+            return Ok(());
+        }
+        let tree = self.repo.find_tree(tree_id)?;
+        let author = Signature::now(author.as_deref().unwrap_or(AUTHOR), AUTHOR_EMAIL)?;
+        let committer = Signature::now(AUTHOR, AUTHOR_EMAIL)?;
+        let parents = parent.iter().collect::<Vec<_>>();
+        self.repo
+            .commit(Some("HEAD"), &author, &committer, message, &tree, &parents)?;
+        Ok(())
+    }
+}
+
+/// Reads the revisions of a repository back from its history, oldest first. Every
+/// commit of an object is a revision, its trailers stand in for the sidecar.
+pub fn read_history(dir: &Path) -> Vec<Revision> {
+    match history(dir) {
+        Ok(revisions) => revisions,
+        Err(error) => {
+            tracing::warn!(%error, ?dir, "failed to read the git history");
+            vec![]
+        }
+    }
+}
+
+fn history(dir: &Path) -> Result<Vec<Revision>> {
+    let repo = Repository::open(dir)?;
+    let mut walk = repo.revwalk()?;
+    if walk.push_head().is_err() {
+        // nothing committed yet
+        return Ok(vec![]);
+    }
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let mut revisions = vec![];
+    for id in walk {
+        let commit = repo.find_commit(id?)?;
+        let mut trailers = trailers(commit.message().unwrap_or_default());
+        // e.g. the commit of the marker
+        let event = match trailers.get("Event").and_then(|event| parse_event(event)) {
+            Some(event) => event,
+            None => continue,
+        };
+        let parent = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+        let delta = match diff.deltas().next() {
+            Some(delta) => delta,
+            None => continue,
+        };
+        // the revision a deletion removed is the last one of the object
+        let file = match event {
+            Change::Deleted => delta.old_file(),
+            Change::Added | Change::Modified => delta.new_file(),
+        };
+        let mut obj = match super::parse_revision(repo.find_blob(file.id())?.content()) {
+            Some(obj) => obj,
+            None => continue,
+        };
+        if let Some(rv) = trailers.remove("Resource-Version") {
+            obj.metadata.resource_version = Some(rv);
+        }
+        let sidecar = Sidecar {
+            event,
+            received: trailers.remove("Received").unwrap_or_default(),
+            context: trailers.remove("Context"),
+            cluster: trailers.remove("Cluster"),
+        };
+        let committed =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64);
+        revisions.push(Revision::new(obj, Some(sidecar), committed));
+    }
+    Ok(revisions)
+}
+
+fn trailers(message: &str) -> HashMap<String, String> {
+    match git2::message_trailers_strs(message) {
+        Ok(trailers) => trailers
+            .iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn parse_event(event: &str) -> Option<Change> {
+    serde_json::from_value(Value::String(event.to_owned())).ok()
+}
+
+/// File of an object in the repository, `<name>.yaml` in the directory its revisions
+/// would have in the other formats
+pub fn object_file(object_dir: &Path, obj: &DynamicObject) -> PathBuf {
+    object_dir.with_file_name(format!("{}.yaml", obj.name_any()))
+}
+
+/// The manager of the most recent managedFields entry, the one which made the revision
+fn manager(obj: &DynamicObject) -> Option<String> {
+    obj.managed_fields()
+        .iter()
+        .enumerate()
+        .max_by_key(|(pos, entry)| (entry.time.as_ref().map(|time| time.0), *pos))
+        .and_then(|(_, entry)| entry.manager.clone())
+}
+
+/// Commit message of a revision, a summary line followed by trailers
+fn message(obj: &DynamicObject, sidecar: &Sidecar) -> String {
+    let kind = obj
+        .types
+        .as_ref()
+        .map(|t| t.kind.clone())
+        .unwrap_or_default();
+    let name = match obj.namespace() {
+        Some(namespace) => format!("{}/{}", namespace, obj.name_any()),
+        None => obj.name_any(),
+    };
+    let event = sidecar.event.watch_type();
+    let mut message = format!("{} {} {}\n\n", kind, name, event.to_lowercase());
+    let mut trailer = |key: &str, value: Option<String>| {
+        if let Some(value) = value {
+            message.push_str(&format!("{}: {}\n", key, value));
+        }
+    };
+    trailer("Kind", Some(kind.clone()));
+    trailer("Name", Some(obj.name_any()));
+    trailer("Namespace", obj.namespace());
+    trailer("Resource-Version", obj.resource_version());
+    trailer("Event", Some(event.to_owned()));
+    trailer("Manager", manager(obj));
+    trailer("Received", Some(sidecar.received.clone()));
+    trailer("Context", sidecar.context.clone());
+    trailer("Cluster", sidecar.cluster.clone());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configmap() -> DynamicObject {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": "cm",
+                "namespace": "default",
+                "resourceVersion": "10",
+                "managedFields": [
+                    {"manager": "kubectl-create", "time": "2024-01-01T00:00:00Z"},
+                    {"manager": "helm", "time": "2024-01-01T00:01:00Z"},
+                ],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn sidecar(event: Change) -> Sidecar {
+        Sidecar {
+            event,
+            received: "2024-01-01T00:01:00.000000Z".to_owned(),
+            context: Some("kind-kind".to_owned()),
+            cluster: None,
+        }
+    }
+
+    fn configmap_at(rv: &str) -> DynamicObject {
+        let mut obj = configmap();
+        obj.metadata.resource_version = Some(rv.to_owned());
+        obj
+    }
+
+    const PATH: &str = "core/v1/ConfigMap/default/cm.yaml";
+
+    #[test]
+    fn test_read_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = GitExport::open(dir.path()).unwrap();
+        fs::write(dir.path().join("marker.json"), "{}").unwrap();
+        git.commit_file(Path::new("marker.json"), "Describe the export layout\n")
+            .unwrap();
+        let path = Path::new(PATH);
+        git.commit(path, &configmap_at("10"), &sidecar(Change::Added))
+            .unwrap();
+        git.commit(path, &configmap_at("11"), &sidecar(Change::Modified))
+            .unwrap();
+        git.commit(path, &configmap_at("12"), &sidecar(Change::Deleted))
+            .unwrap();
+
+        let history = read_history(dir.path());
+        let revisions = history
+            .iter()
+            .map(|r| (r.obj.resource_version().unwrap(), r.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            revisions,
+            vec![
+                ("10".to_owned(), Change::Added),
+                ("11".to_owned(), Change::Modified),
+                ("12".to_owned(), Change::Deleted),
+            ]
+        );
+        assert_eq!(history[2].obj.name_any(), "cm");
+        assert_eq!(
+            history[0].received,
+            SystemTime::from(
+                k8s_openapi::chrono::DateTime::parse_from_rfc3339("2024-01-01T00:01:00Z").unwrap()
+            )
+        );
+    }
+
+    fn commits(dir: &Path) -> Vec<String> {
+        let repo = Repository::open(dir).unwrap();
+        let mut walk = repo.revwalk().unwrap();
+        walk.push_head().unwrap();
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE).unwrap();
+        walk.map(|id| {
+            let commit = repo.find_commit(id.unwrap()).unwrap();
+            commit.message().unwrap().to_owned()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_commit_removes_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = GitExport::open(dir.path()).unwrap();
+        let path = Path::new(PATH);
+        git.commit(path, &configmap_at("10"), &sidecar(Change::Added))
+            .unwrap();
+        assert!(dir.path().join(path).exists());
+        git.commit(path, &configmap_at("11"), &sidecar(Change::Deleted))
+            .unwrap();
+        assert!(!dir.path().join(path).exists());
+        let repo = Repository::open(dir.path()).unwrap();
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(tree.get_path(path).is_err());
+        // deleting again finds nothing to remove
+        git.commit(path, &configmap_at("12"), &sidecar(Change::Deleted))
+            .unwrap();
+        assert_eq!(commits(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn test_commit_skips_seen_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = GitExport::open(dir.path()).unwrap();
+        let path = Path::new(PATH);
+        git.commit(path, &configmap_at("10"), &sidecar(Change::Added))
+            .unwrap();
+        // e.g. the relist of an unchanged object
+        git.commit(path, &configmap_at("10"), &sidecar(Change::Added))
+            .unwrap();
+        assert_eq!(commits(dir.path()).len(), 1);
+    }
+
+    #[test]
+    fn test_commit_trailers() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = GitExport::open(dir.path()).unwrap();
+        git.commit(Path::new(PATH), &configmap(), &sidecar(Change::Added))
+            .unwrap();
+        let message = commits(dir.path()).remove(0);
+        let trailers = trailers(&message);
+        assert_eq!(trailers["Kind"], "ConfigMap");
+        assert_eq!(trailers["Namespace"], "default");
+        assert_eq!(trailers["Resource-Version"], "10");
+        assert_eq!(trailers["Event"], "ADDED");
+        assert_eq!(trailers["Context"], "kind-kind");
+        let repo = Repository::open(dir.path()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.author().name(), Some("helm"));
+        assert_eq!(head.committer().name(), Some(AUTHOR));
+    }
+
+    #[test]
+    fn test_message_added() {
+        let message = message(&configmap(), &sidecar(Change::Added));
+        assert!(
+            message.starts_with("ConfigMap default/cm added\n\n"),
+            "{}",
+            message
+        );
+        assert!(message.contains("\nEvent: ADDED\n"), "{}", message);
+    }
+
+    #[test]
+    fn test_message_modified() {
+        let message = message(&configmap(), &sidecar(Change::Modified));
+        assert!(
+            message.starts_with("ConfigMap default/cm modified\n\n"),
+            "{}",
+            message
+        );
+        assert!(message.contains("\nEvent: MODIFIED\n"), "{}", message);
+    }
+
+    #[test]
+    fn test_message_trailers() {
+        let message = message(&configmap(), &sidecar(Change::Deleted));
+        let trailers = message.lines().skip(2).collect::<Vec<_>>();
+        assert_eq!(
+            trailers,
+            vec![
+                "Kind: ConfigMap",
+                "Name: cm",
+                "Namespace: default",
+                "Resource-Version: 10",
+                "Event: DELETED",
+                "Manager: helm",
+                "Received: 2024-01-01T00:01:00.000000Z",
+                "Context: kind-kind",
+            ]
+        );
+    }
+}
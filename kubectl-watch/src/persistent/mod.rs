@@ -0,0 +1,456 @@
+mod git;
+mod retention;
+mod stream;
+
+use crate::options::{self, ExportFormat};
+pub use retention::Retention;
+
+use anyhow::{bail, Context, Result};
+use k8s_openapi::chrono::{DateTime, SecondsFormat, Utc};
+use kube::api::DynamicObject;
+use kube::api::ResourceExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env::temp_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub fn tmp_store(l_yaml: String, r_yaml: String) -> (PathBuf, PathBuf) {
+    let mut path = temp_dir();
+    path.push("kubectl-watch");
+    fs::create_dir_all(&path).unwrap();
+    let mut minus_file = path.clone();
+    minus_file.push("minus.yaml");
+    let mut plus_file = path.clone();
+    plus_file.push("plus.yaml");
+
+    std::fs::write(&minus_file, l_yaml).unwrap();
+    std::fs::write(&plus_file, r_yaml).unwrap();
+
+    let minus_file = PathBuf::from(&minus_file);
+    let plus_file = PathBuf::from(&plus_file);
+    return (minus_file, plus_file);
+}
+
+/// Version of the export layout written by this build
+const LAYOUT_VERSION: u32 = 2;
+/// File describing the layout of an export directory, relative to it
+pub const MARKER_FILE: &str = "kubectl-watch-export.json";
+/// Directory of cluster scoped objects in place of a namespace
+const CLUSTER_SCOPE: &str = "_cluster";
+/// Directory of the legacy core api group
+const CORE_GROUP: &str = "core";
+/// Extension of the file next to a revision which describes how it was received
+const SIDECAR_EXTENSION: &str = "meta.json";
+
+/// How a revision was received
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Change {
+    /// Added, or part of a list like the initial state
+    Added,
+    /// Modified, or written where the source can't tell it from an add
+    Modified,
+    Deleted,
+}
+
+impl Change {
+    /// Type of the watch event, as the api-server names it
+    fn watch_type(&self) -> &'static str {
+        match self {
+            Change::Added => "ADDED",
+            Change::Modified => "MODIFIED",
+            Change::Deleted => "DELETED",
+        }
+    }
+}
+
+/// The marker file of an export directory
+#[derive(Serialize, Deserialize)]
+struct Marker {
+    version: u32,
+    layout: String,
+    sidecar: String,
+    ndjson: String,
+    bundle: String,
+    git: String,
+}
+
+/// The sidecar of a revision
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    event: Change,
+    /// Wall-clock time the revision was received at, RFC 3339
+    received: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cluster: Option<String>,
+}
+
+/// An export directory. Depending on the format, revisions are written to
+/// `<group>/<version>/<kind>/<namespace>/<name>/<resourceVersion>.yaml` (or `.json`)
+/// with a sidecar `<resourceVersion>.meta.json` next to them, appended to an ndjson
+/// stream of watch events, appended to a tar.gz bundle of the session, or committed to
+/// a git repository.
+pub struct Export {
+    dir: PathBuf,
+    format: ExportFormat,
+    context: Option<String>,
+    cluster: Option<String>,
+    /// The file the ndjson and tar.gz formats append to, opened with the first revision
+    stream: Mutex<Option<stream::Stream>>,
+    /// The repository of the git format
+    git: Option<Mutex<git::GitExport>>,
+    retention: Retention,
+}
+
+/// Opens the export directory of the app, if any
+pub fn open(app: &options::App) -> Result<Option<Export>> {
+    match &app.export {
+        Some(dir) if !dir.is_empty() => {
+            let retention = Retention::new(app);
+            if app.export_format == ExportFormat::Git && !retention.is_empty() {
+                bail!("the git export keeps every revision, retention limits do not apply to it");
+            }
+            if matches!(
+                app.export_format,
+                ExportFormat::Ndjson | ExportFormat::TarGz
+            ) && app.export_max_revisions.is_some()
+            {
+                bail!(
+                    "--export-max-revisions applies to the yaml and json exports, which keep a file per revision"
+                );
+            }
+            Export::open(dir, app.export_format).map(|export| Some(export.retention(retention)))
+        }
+        _ => Ok(None),
+    }
+}
+
+impl Export {
+    /// Opens an export directory, describing its layout in the marker file
+    pub fn open(dir: &str, format: ExportFormat) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        let marker_path = dir.join(MARKER_FILE);
+        if let Ok(data) = fs::read(&marker_path) {
+            let marker: Marker = serde_json::from_slice(&data)
+                .with_context(|| format!("failed to read {:?}", marker_path))?;
+            if marker.version > LAYOUT_VERSION {
+                bail!(
+                    "{:?} was written in export layout {}, this build only knows up to {}",
+                    dir,
+                    marker.version,
+                    LAYOUT_VERSION
+                );
+            }
+        }
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {:?}", dir))?;
+        fs::write(&marker_path, marker()?)
+            .with_context(|| format!("failed to write {:?}", marker_path))?;
+        let git = match format {
+            ExportFormat::Git => {
+                let git = git::GitExport::open(&dir)?;
+                git.commit_file(Path::new(MARKER_FILE), "Describe the export layout\n")?;
+                Some(Mutex::new(git))
+            }
+            _ => None,
+        };
+        Ok(Export {
+            dir,
+            format,
+            context: None,
+            cluster: None,
+            stream: Mutex::new(None),
+            git,
+            retention: Retention::default(),
+        })
+    }
+
+    /// Limits the size of the export directory
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Records the kubeconfig context and cluster url with every revision
+    pub fn cluster(mut self, context: Option<String>, cluster: Option<String>) -> Self {
+        self.context = context;
+        self.cluster = cluster;
+        self
+    }
+
+    fn store(&self, obj: &DynamicObject, change: Change) -> Result<()> {
+        let sidecar = Sidecar {
+            event: change,
+            received: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            context: self.context.clone(),
+            cluster: self.cluster.clone(),
+        };
+        let rv = obj.resource_version().unwrap_or_default();
+        let object_dir = object_dir(obj);
+        let revision_file = |extension: &str| object_dir.join(format!("{}.{}", rv, extension));
+        match self.format {
+            ExportFormat::Yaml | ExportFormat::Json => {
+                fs::create_dir_all(self.dir.join(&object_dir))?;
+                fs::write(
+                    self.dir.join(revision_file(SIDECAR_EXTENSION)),
+                    serde_json::to_vec_pretty(&sidecar)?,
+                )?;
+                let (extension, data) = match self.format {
+                    ExportFormat::Json => ("json", serde_json::to_vec_pretty(obj)?),
+                    _ => ("yaml", serde_yaml::to_string(obj)?.into_bytes()),
+                };
+                fs::write(self.dir.join(revision_file(extension)), &data)?;
+                let object_dir = self.dir.join(&object_dir);
+                self.retention
+                    .apply(&self.dir, Some(&object_dir), None, data.len() as u64);
+            }
+            ExportFormat::Ndjson => {
+                let line = stream::watch_event(obj, sidecar)?;
+                let mut stream = self.stream.lock().unwrap();
+                stream::append_ndjson(&self.dir, &mut stream, &line)?;
+                let active = stream.as_ref().map(|s| s.path());
+                self.retention
+                    .apply(&self.dir, None, active, line.len() as u64);
+            }
+            ExportFormat::TarGz => {
+                let entries = [
+                    (
+                        revision_file("yaml"),
+                        serde_yaml::to_string(obj)?.into_bytes(),
+                    ),
+                    (
+                        revision_file(SIDECAR_EXTENSION),
+                        serde_json::to_vec_pretty(&sidecar)?,
+                    ),
+                ];
+                let mut stream = self.stream.lock().unwrap();
+                let written = stream::append_bundle(&self.dir, &mut stream, &entries)?;
+                let active = stream.as_ref().map(|s| s.path());
+                self.retention.apply(&self.dir, None, active, written);
+            }
+            ExportFormat::Git => {
+                let path = git::object_file(&object_dir, obj);
+                let git = self.git.as_ref().unwrap().lock().unwrap();
+                git.commit(&path, obj, &sidecar)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Directory of the revisions of an object, relative to the export directory
+fn object_dir(obj: &DynamicObject) -> PathBuf {
+    let (api_version, kind) = match &obj.types {
+        Some(types) => (types.api_version.as_str(), types.kind.as_str()),
+        None => ("", ""),
+    };
+    let (group, version) = match api_version.split_once('/') {
+        Some((group, version)) => (group, version),
+        None => (CORE_GROUP, api_version),
+    };
+    let mut path = PathBuf::from(group);
+    path.push(version);
+    path.push(kind);
+    path.push(obj.namespace().unwrap_or_else(|| CLUSTER_SCOPE.to_owned()));
+    path.push(obj.name_any());
+    path
+}
+
+/// Content of the marker file
+fn marker() -> Result<Vec<u8>> {
+    let marker = Marker {
+        version: LAYOUT_VERSION,
+        layout: format!(
+            "<group>/<version>/<kind>/<namespace>/<name>/<resourceVersion>.yaml or .json, \
+             the group of the legacy api group is {:?}, the namespace of cluster scoped objects {:?}",
+            CORE_GROUP, CLUSTER_SCOPE
+        ),
+        sidecar: format!(
+            "<resourceVersion>.{} holding the event, the time it was received at and the cluster",
+            SIDECAR_EXTENSION
+        ),
+        ndjson: format!(
+            "{} and rotated {}, one watch event per line, with the fields of the sidecar",
+            stream::NDJSON_FILE,
+            stream::NDJSON_ROTATED
+        ),
+        bundle: format!(
+            "{}, revision files and sidecars in the layout above",
+            stream::BUNDLE_FILE
+        ),
+        git: "a git repository of <group>/<version>/<kind>/<namespace>/<name>.yaml, a commit per revision \
+              with the kind, name, resourceVersion, event and manager in its message"
+            .to_owned(),
+    };
+    Ok(serde_json::to_vec_pretty(&marker)?)
+}
+
+pub fn store_resource(export: &Option<Export>, obj: &DynamicObject, change: Change) {
+    if let Some(export) = export {
+        if let Err(error) = export.store(obj, change) {
+            tracing::warn!(%error, name = obj.name_any(), "failed to export");
+        }
+    }
+}
+
+/// An exported revision
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub obj: DynamicObject,
+    pub change: Change,
+    /// When it was received, the time the file was written for exports without sidecars
+    pub received: SystemTime,
+}
+
+impl Revision {
+    fn new(obj: DynamicObject, sidecar: Option<Sidecar>, written: SystemTime) -> Self {
+        let received = sidecar
+            .as_ref()
+            .and_then(|sidecar| DateTime::parse_from_rfc3339(&sidecar.received).ok())
+            .map(SystemTime::from)
+            .unwrap_or(written);
+        Revision {
+            obj,
+            change: sidecar.map_or(Change::Modified, |sidecar| sidecar.event),
+            received,
+        }
+    }
+}
+
+/// Loads every exported revision under the export path, oldest first
+pub fn load_history(path: &str) -> Vec<Revision> {
+    load_recording(Path::new(path))
+}
+
+/// Loads every exported revision under the export path, oldest first. Exports of
+/// every layout and format are read, the revisions are found by their extension.
+pub fn load_recording(path: &Path) -> Vec<Revision> {
+    let mut revisions = load_dir(path);
+    revisions.sort_by_key(|r| (revision(&r.obj), r.received));
+    // e.g. the initial list of every session appended to the same ndjson file
+    let mut seen = HashSet::new();
+    revisions.retain(|r| {
+        let types = r.obj.types.as_ref();
+        seen.insert((
+            types.map(|t| t.api_version.clone()),
+            types.map(|t| t.kind.clone()),
+            r.obj.namespace(),
+            r.obj.name_any(),
+            r.obj.resource_version(),
+            r.change,
+        ))
+    });
+    revisions
+}
+
+/// Loads the latest exported revision of every object under the export path which
+/// wasn't deleted
+pub fn load_resources(path: &str) -> Vec<DynamicObject> {
+    let mut latest: HashMap<String, Revision> = HashMap::new();
+    for r in load_dir(Path::new(path)) {
+        let obj = &r.obj;
+        let key = obj
+            .uid()
+            .unwrap_or_else(|| obj.namespace().unwrap_or_default() + "/" + &obj.name_any());
+        let newer = match latest.get(&key) {
+            Some(pre) => revision(obj) > revision(&pre.obj),
+            None => true,
+        };
+        if newer {
+            latest.insert(key, r);
+        }
+    }
+    latest
+        .into_values()
+        .filter(|r| r.change != Change::Deleted)
+        .map(|r| r.obj)
+        .collect()
+}
+
+fn load_dir(dir: &Path) -> Vec<Revision> {
+    // the work tree of the git format only has the latest revisions, its history all
+    let mut revisions = if dir.join(".git").is_dir() {
+        git::read_history(dir)
+    } else {
+        read_dir(dir)
+    };
+    revisions.retain(|revision| has_revision(&revision.obj));
+    revisions
+}
+
+fn read_dir(dir: &Path) -> Vec<Revision> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut revisions = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = retention::strip_compression(&name);
+        if name.starts_with('.') {
+            continue;
+        } else if path.is_dir() {
+            revisions.extend(load_dir(&path));
+        } else if name.ends_with(".ndjson") {
+            revisions.extend(stream::read_ndjson(&path));
+        } else if name.ends_with(".tar.gz") {
+            revisions.extend(stream::read_bundle(&path));
+        } else if is_revision_file(name) {
+            let obj = match retention::read(&path).map(|data| parse_revision(&data)) {
+                Ok(Some(obj)) => obj,
+                _ => {
+                    tracing::debug!(?path, "skipping unreadable export");
+                    continue;
+                }
+            };
+            let sidecar = fs::read(retention::sidecar_path(&path))
+                .ok()
+                .and_then(|data| serde_json::from_slice::<Sidecar>(&data).ok());
+            let written = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            revisions.push(Revision::new(obj, sidecar, written));
+        }
+    }
+    revisions
+}
+
+/// Revisions are told apart by their resource version
+pub fn has_revision(obj: &DynamicObject) -> bool {
+    let found = obj.metadata.resource_version.is_some();
+    if !found {
+        tracing::warn!(
+            name = obj.metadata.name,
+            "skipping object without resourceVersion"
+        );
+    }
+    found
+}
+
+/// Revision files are yaml or json, the other json files of the export directory
+/// are sidecars, the marker and the resume state
+fn is_revision_file(name: &str) -> bool {
+    if name.starts_with('.') || name == MARKER_FILE {
+        return false;
+    }
+    name.ends_with(".yaml")
+        || (name.ends_with(".json") && !name.ends_with(&format!(".{}", SIDECAR_EXTENSION)))
+}
+
+/// Parses a yaml or json revision file, json being yaml as well
+fn parse_revision(data: &[u8]) -> Option<DynamicObject> {
+    serde_yaml::from_slice(data).ok()
+}
+
+/// Resource versions are opaque, but in practice increasing numbers
+fn revision(obj: &DynamicObject) -> u64 {
+    obj.resource_version()
+        .and_then(|rv| rv.parse().ok())
+        .unwrap_or_default()
+}
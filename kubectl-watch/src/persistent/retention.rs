@@ -0,0 +1,541 @@
+use super::{is_revision_file, stream::NDJSON_FILE, SIDECAR_EXTENSION};
+use crate::options::{self, ExportCompression};
+
+use anyhow::Result;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Minimum time between two sweeps of the whole export directory
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits of an export directory. The latest revision file of every object is always
+/// kept, the ndjson file and bundle written to are never removed. Rotated ndjson files
+/// and bundles of past sessions are removed whole, whatever revisions they hold.
+#[derive(Default)]
+pub struct Retention {
+    max_revisions: Option<usize>,
+    limits: Limits,
+    sweep: Arc<Mutex<Sweep>>,
+}
+
+/// Limits applied by the sweeps of the whole export directory
+#[derive(Default, Clone, Copy)]
+struct Limits {
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    /// Compress revisions once a newer one of the same object arrived, and rotated
+    /// ndjson files
+    compress: Option<ExportCompression>,
+}
+
+/// State of the sweeps of the whole export directory
+#[derive(Default)]
+struct Sweep {
+    at: Option<Instant>,
+    /// A sweep is running on its own thread
+    running: bool,
+    /// Size of the export directory, as of the last sweep plus what was written since
+    bytes: u64,
+    /// The latest revisions alone exceed the size limit
+    oversized: bool,
+}
+
+/// A file of the export directory retention applies to
+struct Exported {
+    path: PathBuf,
+    /// The sidecar of a revision file
+    sidecar: Option<PathBuf>,
+    bytes: u64,
+    modified: SystemTime,
+    /// The latest revision of an object, or a file written to
+    keep: bool,
+}
+
+impl Retention {
+    pub fn new(app: &options::App) -> Self {
+        Retention {
+            max_revisions: app.export_max_revisions,
+            limits: Limits {
+                max_age: app.export_max_age,
+                max_bytes: app.export_max_bytes,
+                compress: app.export_compress,
+            },
+            sweep: Arc::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_revisions.is_none()
+            && self.limits.max_age.is_none()
+            && self.limits.max_bytes.is_none()
+            && self.limits.compress.is_none()
+    }
+
+    /// Applies the limits to the object a revision was just written for, and to the
+    /// whole export directory once in a while or once `written` bytes more exceed
+    /// the size limit. The whole directory is swept on a thread of its own, not to
+    /// hold up the events meanwhile.
+    pub fn apply(
+        &self,
+        dir: &Path,
+        object_dir: Option<&Path>,
+        active: Option<&Path>,
+        written: u64,
+    ) {
+        if self.is_empty() {
+            return;
+        }
+        if let Some(object_dir) = object_dir {
+            if let Err(error) = self.apply_object(object_dir) {
+                tracing::warn!(?object_dir, %error, "failed to apply export retention");
+            }
+        }
+        let mut sweep = self.sweep.lock().unwrap();
+        sweep.bytes += written;
+        let interval_passed = match sweep.at {
+            Some(at) => at.elapsed() >= SWEEP_INTERVAL,
+            None => true,
+        };
+        let limits = self.limits;
+        let due = interval_passed
+            || (!sweep.oversized && limits.max_bytes.is_some_and(|max| sweep.bytes > max));
+        if !due || sweep.running {
+            return;
+        }
+        sweep.running = true;
+        let state = Arc::clone(&self.sweep);
+        let dir = dir.to_owned();
+        let active = active.map(Path::to_owned);
+        thread::spawn(move || loop {
+            let started_at = {
+                let mut sweep = state.lock().unwrap();
+                sweep.at = Some(Instant::now());
+                sweep.bytes
+            };
+            let swept = limits.sweep(&dir, active.as_deref());
+            let mut sweep = state.lock().unwrap();
+            let again = match swept {
+                Ok(bytes) => {
+                    // with what was written while sweeping
+                    sweep.bytes = bytes + sweep.bytes.saturating_sub(started_at);
+                    sweep.oversized = limits.max_bytes.is_some_and(|max| bytes > max);
+                    // what was written meanwhile exceeds the limit already
+                    !sweep.oversized && limits.max_bytes.is_some_and(|max| sweep.bytes > max)
+                }
+                Err(error) => {
+                    tracing::warn!(?dir, %error, "failed to apply export retention");
+                    false
+                }
+            };
+            if !again {
+                sweep.running = false;
+                return;
+            }
+        });
+    }
+
+    fn apply_object(&self, object_dir: &Path) -> Result<()> {
+        let mut revisions = revision_files(object_dir)?;
+        revisions.sort_by_key(|(rv, _)| *rv);
+        if let Some(max) = self.max_revisions.filter(|max| *max > 0) {
+            let removed = revisions.len().saturating_sub(max);
+            for (_, path) in revisions.drain(..removed) {
+                remove_revision(&path)?;
+            }
+        }
+        if let Some(compression) = self.limits.compress {
+            // the latest revision stays readable as is
+            revisions.pop();
+            for (_, path) in revisions {
+                if compression_of(&path).is_none() {
+                    compress(&path, compression)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Limits {
+    /// Removes what is beyond the limits, returns the size left
+    fn sweep(&self, dir: &Path, active: Option<&Path>) -> Result<u64> {
+        let mut files = vec![];
+        collect(dir, active, &mut files)?;
+
+        if let Some(compression) = self.compress {
+            for file in files
+                .iter_mut()
+                .filter(|file| is_rotated_ndjson(&file.path))
+            {
+                if !file.keep && compression_of(&file.path).is_none() {
+                    file.path = compress(&file.path, compression)?;
+                    file.bytes = fs::metadata(&file.path)?.len();
+                }
+            }
+        }
+
+        files.sort_by_key(|file| file.modified);
+        let mut total: u64 = files.iter().map(|file| file.bytes).sum();
+        let now = SystemTime::now();
+        for file in files.iter().filter(|file| !file.keep) {
+            let expired = self.max_age.is_some_and(|max_age| {
+                now.duration_since(file.modified)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let oversized = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+            if !expired && !oversized {
+                continue;
+            }
+            // compressed or removed by the writer meanwhile
+            match fs::remove_file(&file.path) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+            if let Some(sidecar) = &file.sidecar {
+                let _ = fs::remove_file(sidecar);
+            }
+            total -= file.bytes;
+            if let Some(parent) = file.path.parent() {
+                // the directory of an object without revisions left
+                let _ = fs::remove_dir(parent);
+            }
+        }
+        if self.max_bytes.is_some_and(|max_bytes| total > max_bytes) {
+            tracing::warn!(
+                ?dir,
+                bytes = total,
+                "export exceeds --export-max-bytes with only the latest revisions left"
+            );
+        }
+        Ok(total)
+    }
+}
+
+/// Collects the revision files, ndjson files and bundles under a directory
+fn collect(dir: &Path, active: Option<&Path>, files: &mut Vec<Exported>) -> io::Result<()> {
+    let mut revisions = vec![];
+    for entry in fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = strip_compression(&name);
+        if path.is_dir() {
+            collect(&path, active, files)?;
+            continue;
+        }
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            // removed meanwhile
+            Err(_) => continue,
+        };
+        let mut file = Exported {
+            sidecar: None,
+            bytes: meta.len(),
+            modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            keep: Some(path.as_path()) == active || name == NDJSON_FILE,
+            path,
+        };
+        if name.ends_with(".ndjson") || name.ends_with(".tar.gz") {
+            files.push(file);
+        } else if is_revision_file(name) {
+            let sidecar = sidecar_path(&file.path);
+            file.bytes += fs::metadata(&sidecar)
+                .map(|meta| meta.len())
+                .unwrap_or_default();
+            file.sidecar = Some(sidecar);
+            revisions.push((revision_number(&file.path), file));
+        }
+    }
+    // the latest revision of the object
+    if let Some((_, latest)) = revisions.iter_mut().max_by_key(|(rv, _)| *rv) {
+        latest.keep = true;
+    }
+    files.extend(revisions.into_iter().map(|(_, file)| file));
+    Ok(())
+}
+
+/// The revision files of the directory of an object with their resource versions
+fn revision_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut revisions = vec![];
+    for entry in fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_revision_file(strip_compression(&name)) {
+            let path = entry.path();
+            revisions.push((revision_number(&path), path));
+        }
+    }
+    Ok(revisions)
+}
+
+fn remove_revision(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(sidecar_path(path)) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Resource version in the name of a revision file, its name up to the first dot
+fn revision_number(path: &Path) -> u64 {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.split('.')
+        .next()
+        .and_then(|rv| rv.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The sidecar of a revision file, compressed or not
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let rv = name.split('.').next().unwrap_or_default();
+    path.with_file_name(format!("{}.{}", rv, SIDECAR_EXTENSION))
+}
+
+/// A rotated ndjson file, compressed or not
+fn is_rotated_ndjson(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = strip_compression(&name);
+    name.starts_with("events-") && name.ends_with(".ndjson")
+}
+
+fn compression_of(path: &Path) -> Option<ExportCompression> {
+    match path.extension()?.to_str()? {
+        "gz" if !path.to_string_lossy().ends_with(".tar.gz") => Some(ExportCompression::Gzip),
+        "zst" => Some(ExportCompression::Zstd),
+        _ => None,
+    }
+}
+
+/// Name of a file without the extension of its compression
+pub fn strip_compression(name: &str) -> &str {
+    if name.ends_with(".tar.gz") {
+        return name;
+    }
+    name.strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(name)
+}
+
+/// Replaces a file with its compressed version, keeping its modification time
+fn compress(path: &Path, compression: ExportCompression) -> io::Result<PathBuf> {
+    let data = fs::read(path)?;
+    let modified = fs::metadata(path)?.modified()?;
+    let extension = match compression {
+        ExportCompression::Gzip => "gz",
+        ExportCompression::Zstd => "zst",
+    };
+    let mut compressed = path.as_os_str().to_owned();
+    compressed.push(".");
+    compressed.push(extension);
+    let compressed = PathBuf::from(compressed);
+
+    let file = File::create(&compressed)?;
+    match compression {
+        ExportCompression::Gzip => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?.set_modified(modified)?;
+        }
+        ExportCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, 0)?;
+            encoder.write_all(&data)?;
+            encoder.finish()?.set_modified(modified)?;
+        }
+    }
+    fs::remove_file(path)?;
+    Ok(compressed)
+}
+
+/// Opens an exported file, decompressing it by its extension
+pub fn open(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    Ok(match compression_of(path) {
+        Some(ExportCompression::Gzip) => Box::new(MultiGzDecoder::new(file)),
+        Some(ExportCompression::Zstd) => Box::new(zstd::Decoder::new(file)?),
+        None => Box::new(file),
+    })
+}
+
+/// Reads an exported file, decompressing it by its extension
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    /// Writes a revision of 100 bytes with its sidecar, modified `age` ago
+    fn revision(object_dir: &Path, rv: u64, age: Duration) -> PathBuf {
+        fs::create_dir_all(object_dir).unwrap();
+        let path = object_dir.join(format!("{}.yaml", rv));
+        fs::write(&path, [b'a'; 98]).unwrap();
+        fs::write(sidecar_path(&path), "{}").unwrap();
+        set_age(&path, age);
+        path
+    }
+
+    fn set_age(path: &Path, age: Duration) {
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    fn remaining(dir: &Path) -> Vec<u64> {
+        let mut files = vec![];
+        collect(dir, None, &mut files).unwrap();
+        let mut revisions = files
+            .iter()
+            .map(|file| revision_number(&file.path))
+            .collect::<Vec<_>>();
+        revisions.sort();
+        revisions
+    }
+
+    #[test]
+    fn test_strip_compression() {
+        assert_eq!(strip_compression("10.yaml.gz"), "10.yaml");
+        assert_eq!(strip_compression("10.json.zst"), "10.json");
+        assert_eq!(strip_compression("10.yaml"), "10.yaml");
+        assert_eq!(
+            strip_compression("events-20240101T000000.000000Z.ndjson.zst"),
+            "events-20240101T000000.000000Z.ndjson"
+        );
+        assert_eq!(
+            strip_compression("session-20240101T000000.000000Z.tar.gz"),
+            "session-20240101T000000.000000Z.tar.gz"
+        );
+    }
+
+    #[test]
+    fn test_latest_revision_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let object_dir = dir.path().join("core/v1/ConfigMap/default/a");
+        revision(&object_dir, 9, 3 * HOUR);
+        revision(&object_dir, 10, 2 * HOUR);
+        let limits = Limits {
+            max_age: Some(HOUR),
+            max_bytes: Some(0),
+            compress: None,
+        };
+
+        let bytes = limits.sweep(dir.path(), None).unwrap();
+        assert_eq!(remaining(dir.path()), vec![10]);
+        assert_eq!(bytes, 100);
+        assert!(sidecar_path(&object_dir.join("10.yaml")).exists());
+        assert!(!sidecar_path(&object_dir.join("9.yaml")).exists());
+    }
+
+    #[test]
+    fn test_written_files_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let ndjson = dir.path().join(NDJSON_FILE);
+        let rotated = dir.path().join("events-20240101T000000.000000Z.ndjson");
+        let bundle = dir.path().join("session-20240101T000000.000000Z.tar.gz");
+        let active = dir.path().join("session-20240102T000000.000000Z.tar.gz");
+        for path in [&ndjson, &rotated, &bundle, &active] {
+            fs::write(path, "{}").unwrap();
+            set_age(path, 2 * HOUR);
+        }
+        let limits = Limits {
+            max_age: Some(HOUR),
+            ..Limits::default()
+        };
+
+        limits.sweep(dir.path(), Some(&active)).unwrap();
+        assert!(ndjson.exists());
+        assert!(active.exists());
+        assert!(!rotated.exists());
+        assert!(!bundle.exists());
+    }
+
+    #[test]
+    fn test_max_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        for rv in [8, 9, 10, 11] {
+            revision(dir.path(), rv, Duration::ZERO);
+        }
+        let retention = Retention {
+            max_revisions: Some(2),
+            ..Retention::default()
+        };
+
+        retention.apply_object(dir.path()).unwrap();
+        // ordered by resource version as a number, not as a name
+        assert_eq!(remaining(dir.path()), vec![10, 11]);
+        assert!(!sidecar_path(&dir.path().join("9.yaml")).exists());
+    }
+
+    #[test]
+    fn test_compress_all_but_latest() {
+        let dir = tempfile::tempdir().unwrap();
+        revision(dir.path(), 1, Duration::ZERO);
+        revision(dir.path(), 2, Duration::ZERO);
+        let retention = Retention {
+            limits: Limits {
+                compress: Some(ExportCompression::Gzip),
+                ..Limits::default()
+            },
+            ..Retention::default()
+        };
+
+        retention.apply_object(dir.path()).unwrap();
+        assert!(dir.path().join("1.yaml.gz").exists());
+        assert!(dir.path().join("2.yaml").exists());
+        assert_eq!(read(&dir.path().join("1.yaml.gz")).unwrap(), [b'a'; 98]);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("core/v1/ConfigMap/default/a");
+        let b = dir.path().join("core/v1/ConfigMap/default/b");
+        revision(&a, 1, 3 * HOUR);
+        revision(&a, 2, HOUR / 2);
+        revision(&a, 3, Duration::ZERO);
+        revision(&b, 4, 3 * HOUR);
+        let limits = Limits {
+            max_age: Some(HOUR),
+            ..Limits::default()
+        };
+
+        limits.sweep(dir.path(), None).unwrap();
+        assert_eq!(remaining(dir.path()), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_max_bytes_removes_oldest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("core/v1/ConfigMap/default/a");
+        let b = dir.path().join("core/v1/ConfigMap/default/b");
+        revision(&a, 1, 3 * HOUR);
+        revision(&a, 2, 2 * HOUR);
+        revision(&a, 3, HOUR);
+        revision(&b, 4, 4 * HOUR);
+        revision(&b, 5, Duration::ZERO);
+        let limits = Limits {
+            max_bytes: Some(350),
+            ..Limits::default()
+        };
+
+        let bytes = limits.sweep(dir.path(), None).unwrap();
+        // the oldest first, whatever the object
+        assert_eq!(remaining(dir.path()), vec![2, 3, 5]);
+        assert_eq!(bytes, 300);
+    }
+}
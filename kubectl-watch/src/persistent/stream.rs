@@ -0,0 +1,207 @@
+use super::{
+    is_revision_file, marker, parse_revision, retention, Revision, Sidecar, MARKER_FILE,
+    SIDECAR_EXTENSION,
+};
+
+use anyhow::Result;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use k8s_openapi::chrono::Utc;
+use kube::api::DynamicObject;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// File the ndjson format appends to, relative to the export directory
+pub const NDJSON_FILE: &str = "events.ndjson";
+/// Name of a rotated ndjson file
+pub const NDJSON_ROTATED: &str = "events-<rotated at>.ndjson";
+/// Name of the bundle of a tar.gz session
+pub const BUNDLE_FILE: &str = "session-<started at>.tar.gz";
+/// Size at which the ndjson file is rotated
+const NDJSON_ROTATE_BYTES: u64 = 64 * 1024 * 1024;
+/// Timestamp in the names of rotated files and bundles
+const FILE_TIMESTAMP: &str = "%Y%m%dT%H%M%S%.6fZ";
+
+/// A file appended to
+pub struct Stream {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl Stream {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A line of the ndjson format, a watch event as `kubectl get -w -o json --output-watch-events`
+/// prints them with the fields of the sidecar
+#[derive(Serialize, Deserialize)]
+struct WatchEvent<T> {
+    #[serde(rename = "type")]
+    type_: String,
+    object: T,
+    #[serde(flatten)]
+    sidecar: Sidecar,
+}
+
+pub fn watch_event(obj: &DynamicObject, sidecar: Sidecar) -> Result<Vec<u8>> {
+    let event = WatchEvent {
+        type_: sidecar.event.watch_type().to_owned(),
+        object: obj,
+        sidecar,
+    };
+    let mut line = serde_json::to_vec(&event)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Appends a line to the ndjson file, rotating it once it grew too big
+pub fn append_ndjson(dir: &Path, stream: &mut Option<Stream>, line: &[u8]) -> Result<()> {
+    let path = dir.join(NDJSON_FILE);
+    if stream
+        .as_ref()
+        .is_some_and(|s| s.len > 0 && s.len + line.len() as u64 > NDJSON_ROTATE_BYTES)
+    {
+        *stream = None;
+        let rotated = format!("events-{}.ndjson", Utc::now().format(FILE_TIMESTAMP));
+        fs::rename(&path, dir.join(rotated))?;
+    }
+    if stream.is_none() {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        *stream = Some(Stream { path, file, len });
+    }
+    let stream = stream.as_mut().unwrap();
+    stream.file.write_all(line)?;
+    stream.len += line.len() as u64;
+    Ok(())
+}
+
+/// Appends files to the bundle of the session, which starts with the marker, returns
+/// the compressed size of the files.
+///
+/// Every append is a gzip member of its own holding whole tar entries, so the bundle
+/// can be read whenever the session stops. It has no end-of-archive blocks, which
+/// tar readers accept.
+pub fn append_bundle(
+    dir: &Path,
+    stream: &mut Option<Stream>,
+    entries: &[(PathBuf, Vec<u8>)],
+) -> Result<u64> {
+    let mut files = vec![];
+    if stream.is_none() {
+        let name = format!("session-{}.tar.gz", Utc::now().format(FILE_TIMESTAMP));
+        let path = dir.join(name);
+        let file = File::create(&path)?;
+        *stream = Some(Stream { path, file, len: 0 });
+        files.push((PathBuf::from(MARKER_FILE), marker()?));
+    }
+    files.extend(entries.iter().cloned());
+
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, path, data.as_slice())?;
+    }
+    // taken before the builder is dropped, which writes the end-of-archive blocks
+    let tar = std::mem::take(builder.get_mut());
+
+    let stream = stream.as_mut().unwrap();
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&tar)?;
+    let member = encoder.finish()?;
+    stream.file.write_all(&member)?;
+    stream.len += member.len() as u64;
+    Ok(member.len() as u64)
+}
+
+/// Reads the revisions of an ndjson file, lines of other streams are skipped
+pub fn read_ndjson(path: &Path) -> Vec<Revision> {
+    let file = match retention::open(path) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    let mut revisions = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                tracing::debug!(?path, %error, "stopped reading export");
+                break;
+            }
+        };
+        match serde_json::from_str::<WatchEvent<DynamicObject>>(&line) {
+            Ok(event) => revisions.push(Revision::new(
+                event.object,
+                Some(event.sidecar),
+                SystemTime::UNIX_EPOCH,
+            )),
+            Err(_) => tracing::debug!(?path, "skipping unreadable export line"),
+        }
+    }
+    revisions
+}
+
+/// Reads the revisions of a bundle
+pub fn read_bundle(path: &Path) -> Vec<Revision> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    let mut archive = tar::Archive::new(MultiGzDecoder::new(file));
+    let mut files = HashMap::new();
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                tracing::debug!(?path, %error, "stopped reading export");
+                break;
+            }
+        };
+        let name = match entry.path() {
+            Ok(name) => name.into_owned(),
+            Err(_) => continue,
+        };
+        let mtime = entry.header().mtime().unwrap_or_default();
+        let mut data = vec![];
+        if entry.read_to_end(&mut data).is_ok() {
+            files.insert(name, (mtime, data));
+        }
+    }
+
+    let mut revisions = vec![];
+    for (name, (mtime, data)) in &files {
+        let file_name = name.file_name().unwrap_or_default().to_string_lossy();
+        if !is_revision_file(&file_name) {
+            continue;
+        }
+        let obj = match parse_revision(data) {
+            Some(obj) => obj,
+            None => continue,
+        };
+        let sidecar = files
+            .get(&name.with_extension(SIDECAR_EXTENSION))
+            .and_then(|(_, data)| serde_json::from_slice::<Sidecar>(data).ok());
+        let written = SystemTime::UNIX_EPOCH + Duration::from_secs(*mtime);
+        revisions.push(Revision::new(obj, sidecar, written));
+    }
+    revisions
+}
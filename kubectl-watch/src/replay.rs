@@ -0,0 +1,43 @@
+use crate::kube::Event;
+use crate::options;
+use crate::persistent::{self, Change};
+
+use anyhow::{bail, Result};
+use std::{path::Path, time::Duration};
+use tokio::sync::mpsc::{channel, Receiver};
+
+/// Plays an export directory back as watch events, paced by the time each revision
+/// was recorded at if a speed is given.
+pub fn replay(args: &options::Replay) -> Result<Receiver<Event>> {
+    let dir = Path::new(&args.dir);
+    if !dir.is_dir() {
+        bail!("{:?} is not an export directory", args.dir);
+    }
+    let recording = persistent::load_recording(dir);
+    tracing::info!(dir = args.dir, revisions = recording.len(), "replaying");
+    let speed = args.speed.filter(|speed| *speed > 0.0);
+
+    let (tx, rx) = channel(32);
+    tokio::spawn(async move {
+        let mut last = None;
+        for revision in recording {
+            if let (Some(speed), Some(last)) = (speed, last) {
+                let pause = revision
+                    .received
+                    .duration_since(last)
+                    .unwrap_or(Duration::ZERO);
+                tokio::time::sleep(pause.div_f64(speed)).await;
+            }
+            last = Some(revision.received);
+            let event = match revision.change {
+                Change::Deleted => Event::Deleted(revision.obj),
+                Change::Added => Event::Added(revision.obj),
+                Change::Modified => Event::Applied(revision.obj),
+            };
+            if tx.send(event).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(rx)
+}